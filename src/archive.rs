@@ -0,0 +1,292 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use futures::{channel::mpsc, executor::block_on, SinkExt};
+
+// Size of the chunks we hand over to hyper. Small enough to keep memory low, big
+// enough to not wake up the webserver for every single tar header.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::Zip => "application/zip",
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(ArchiveFormat::Tar),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err("Archive format has to be either tar or zip"),
+        }
+    }
+}
+
+pub fn archive_name(folder: &Path, format: ArchiveFormat) -> String {
+    format!("{}.{}", folder_name(folder), format.extension())
+}
+
+pub fn folder_name(folder: &Path) -> String {
+    folder
+        .canonicalize()
+        .ok()
+        .and_then(|folder| folder.file_name().map(|name| name.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "torshare".into())
+}
+
+/// Writes `folder` as an archive into the returned stream while the client reads it.
+/// Nothing gets buffered on disk, the archive only ever exists in flight.
+pub fn stream_folder(folder: PathBuf, format: ArchiveFormat) -> mpsc::Receiver<io::Result<Vec<u8>>> {
+    let (sender, receiver) = mpsc::channel::<io::Result<Vec<u8>>>(4);
+
+    tokio::task::spawn_blocking(move || {
+        let mut error_sender = sender.clone();
+        let writer = BufWriter::with_capacity(CHUNK_SIZE, ChannelWriter { sender });
+        let result = match format {
+            ArchiveFormat::Tar => write_tar(&folder, writer),
+            ArchiveFormat::Zip => write_zip(&folder, writer),
+        };
+        if let Err(err) = result {
+            // Let hyper abort the response so the client doesn't think it got
+            // a complete archive.
+            let _ = block_on(error_sender.send(Err(err)));
+        }
+    });
+
+    receiver
+}
+
+struct ChannelWriter {
+    sender: mpsc::Sender<io::Result<Vec<u8>>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        block_on(self.sender.send(Ok(buf.to_vec())))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_tar<W: Write>(folder: &Path, writer: W) -> io::Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    // Never leave the shared folder through a symlink.
+    builder.follow_symlinks(false);
+    builder.append_dir_all(folder_name(folder), folder)?;
+    builder.into_inner()?.flush()
+}
+
+fn write_zip<W: Write>(folder: &Path, writer: W) -> io::Result<()> {
+    let mut zip = ZipStreamWriter::new(writer);
+    let root = PathBuf::from(folder_name(folder));
+    zip.add_directory(&root)?;
+    append_dir_to_zip(&mut zip, folder, &root)?;
+    zip.finish()?.flush()
+}
+
+fn append_dir_to_zip<W: Write>(zip: &mut ZipStreamWriter<W>, dir: &Path, archive_dir: &Path) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let file_type = entry.file_type()?;
+        let archive_path = archive_dir.join(entry.file_name());
+        if file_type.is_dir() {
+            zip.add_directory(&archive_path)?;
+            append_dir_to_zip(zip, &entry.path(), &archive_path)?;
+        } else if file_type.is_file() {
+            zip.add_file(&archive_path, &mut File::open(entry.path())?)?;
+        }
+        // Symlinks and special files are skipped, same as for tar we don't want
+        // to leave the shared folder.
+    }
+    Ok(())
+}
+
+struct ZipEntry {
+    name: Vec<u8>,
+    crc32: u32,
+    size: u32,
+    offset: u32,
+    is_directory: bool,
+}
+
+/// Minimal zip writer which only needs `Write`, not `Seek`. Entries are stored
+/// uncompressed and their sizes/checksums are written in a data descriptor after
+/// the data. No zip64, so single entries and the whole archive are limited to 4GiB.
+struct ZipStreamWriter<W: Write> {
+    writer: W,
+    written: u64,
+    entries: Vec<ZipEntry>,
+}
+
+const ZIP_VERSION: u16 = 20;
+const ZIP_FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const ZIP_FLAG_UTF8: u16 = 1 << 11;
+// 1980-01-01 00:00, the earliest date a zip file can represent.
+const ZIP_DOS_TIME: u16 = 0;
+const ZIP_DOS_DATE: u16 = (1 << 5) | 1;
+
+impl<W: Write> ZipStreamWriter<W> {
+    fn new(writer: W) -> Self {
+        ZipStreamWriter { writer, written: 0, entries: Vec::new() }
+    }
+
+    fn add_directory(&mut self, path: &Path) -> io::Result<()> {
+        let mut name = zip_entry_name(path);
+        name.push(b'/');
+        let offset = self.offset()?;
+        self.write_local_header(&name, 0)?;
+        self.entries.push(ZipEntry { name, crc32: 0, size: 0, offset, is_directory: true });
+        Ok(())
+    }
+
+    fn add_file<R: io::Read>(&mut self, path: &Path, reader: &mut R) -> io::Result<()> {
+        let name = zip_entry_name(path);
+        let offset = self.offset()?;
+        self.write_local_header(&name, ZIP_FLAG_DATA_DESCRIPTOR)?;
+
+        let mut hasher = crc32fast::Hasher::new();
+        let mut size: u64 = 0;
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let read = reader.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            self.write_all(&buf[..read])?;
+            size += read as u64;
+        }
+        let size = to_u32(size)?;
+        let crc32 = hasher.finalize();
+
+        self.write_u32(0x08074b50)?;
+        self.write_u32(crc32)?;
+        self.write_u32(size)?;
+        self.write_u32(size)?;
+
+        self.entries.push(ZipEntry { name, crc32, size, offset, is_directory: false });
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<W> {
+        let central_directory_offset = self.offset()?;
+        let entries = std::mem::take(&mut self.entries);
+        for entry in &entries {
+            let (flags, external_attributes) = if entry.is_directory {
+                (ZIP_FLAG_UTF8, (0o040755 << 16) | 0x10)
+            } else {
+                (ZIP_FLAG_UTF8 | ZIP_FLAG_DATA_DESCRIPTOR, 0o100644 << 16)
+            };
+            self.write_u32(0x02014b50)?;
+            // Made by unix, so the external attributes are interpreted as mode.
+            self.write_u16((3 << 8) | ZIP_VERSION)?;
+            self.write_u16(ZIP_VERSION)?;
+            self.write_u16(flags)?;
+            self.write_u16(0)?;
+            self.write_u16(ZIP_DOS_TIME)?;
+            self.write_u16(ZIP_DOS_DATE)?;
+            self.write_u32(entry.crc32)?;
+            self.write_u32(entry.size)?;
+            self.write_u32(entry.size)?;
+            self.write_u16(entry.name.len() as u16)?;
+            self.write_u16(0)?;
+            self.write_u16(0)?;
+            self.write_u16(0)?;
+            self.write_u16(0)?;
+            self.write_u32(external_attributes)?;
+            self.write_u32(entry.offset)?;
+            self.write_all(&entry.name)?;
+        }
+        let central_directory_size = self.offset()? - central_directory_offset;
+
+        if entries.len() > u16::MAX as usize {
+            return Err(io::Error::new(io::ErrorKind::Other, "Too many files for a zip archive, use tar instead"));
+        }
+        self.write_u32(0x06054b50)?;
+        self.write_u16(0)?;
+        self.write_u16(0)?;
+        self.write_u16(entries.len() as u16)?;
+        self.write_u16(entries.len() as u16)?;
+        self.write_u32(central_directory_size)?;
+        self.write_u32(central_directory_offset)?;
+        self.write_u16(0)?;
+        Ok(self.writer)
+    }
+
+    fn write_local_header(&mut self, name: &[u8], flags: u16) -> io::Result<()> {
+        self.write_u32(0x04034b50)?;
+        self.write_u16(ZIP_VERSION)?;
+        self.write_u16(flags | ZIP_FLAG_UTF8)?;
+        self.write_u16(0)?;
+        self.write_u16(ZIP_DOS_TIME)?;
+        self.write_u16(ZIP_DOS_DATE)?;
+        // crc32 and sizes are either 0 for directories or follow in the data descriptor
+        self.write_u32(0)?;
+        self.write_u32(0)?;
+        self.write_u32(0)?;
+        self.write_u16(name.len() as u16)?;
+        self.write_u16(0)?;
+        self.write_all(name)
+    }
+
+    fn offset(&self) -> io::Result<u32> {
+        to_u32(self.written)
+    }
+
+    fn write_u16(&mut self, value: u16) -> io::Result<()> {
+        self.write_all(&value.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, value: u32) -> io::Result<()> {
+        self.write_all(&value.to_le_bytes())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)?;
+        self.written += buf.len() as u64;
+        Ok(())
+    }
+}
+
+fn zip_entry_name(path: &Path) -> Vec<u8> {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<String>>()
+        .join("/")
+        .into_bytes()
+}
+
+fn to_u32(value: u64) -> io::Result<u32> {
+    if value > u32::MAX as u64 {
+        return Err(io::Error::new(io::ErrorKind::Other, "Folder is too big for a zip archive, use tar instead"));
+    }
+    Ok(value as u32)
+}
@@ -1,9 +1,9 @@
-use std::io::{stdout, Write};
+use std::{io::{stderr, stdout, Write}, sync::atomic::{AtomicBool, Ordering}};
 use structopt::StructOpt;
 use termion;
 pub use termion::color as Color;
 
-use crate::{download_file::DownloadOptions, share::ShareOptions};
+use crate::{download_file::DownloadOptions, keys::KeysCommand, receive::ReceiveOptions, share::ShareOptions, upload_file::UploadOptions};
 
 
 
@@ -20,6 +20,21 @@ pub enum CliOptions {
     Share {
         #[structopt(flatten)]
         share_options: ShareOptions
+    },
+    /// Let others upload files into a folder
+    Receive {
+        #[structopt(flatten)]
+        receive_options: ReceiveOptions
+    },
+    /// Upload a file to someone running `torshare receive`
+    Upload {
+        #[structopt(flatten)]
+        upload_options: UploadOptions
+    },
+    /// Manage the identities used with `torshare share --id`
+    Keys {
+        #[structopt(subcommand)]
+        keys_command: KeysCommand
     }
     
 }
@@ -29,10 +44,23 @@ pub fn save_cursor_position() {
     //print!("{}", termion::cursor::Save);
 }
 
+// Set when stdout carries data, e.g. `torshare download <url> -`
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Prints all further status lines to stderr instead of stdout
+pub fn print_status_to_stderr() {
+    STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
 pub fn print_status_line<S: AsRef<str>>(color: &dyn termion::color::Color, text: S) {
     let text = text.as_ref();
 
-    stdout().write_all(
+    let mut output: Box<dyn Write> = if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+        Box::new(stderr())
+    } else {
+        Box::new(stdout())
+    };
+    output.write_all(
         format!(
             //"{}{}{}{}⬤{} {}\n",
             "{}⬤{} {}\n",
@@ -43,5 +71,5 @@ pub fn print_status_line<S: AsRef<str>>(color: &dyn termion::color::Color, text:
         )
         .as_bytes(),
     );
-    stdout().flush().unwrap();
+    output.flush().unwrap();
 }
@@ -0,0 +1,95 @@
+use std::{
+    convert::TryInto,
+    fmt,
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::DirBuilderExt,
+    path::Path,
+    str::FromStr,
+};
+
+use data_encoding::BASE32_NOPAD;
+use rand::{thread_rng, Rng};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// Onion service client authorization (v3). The sharing side puts the public key
+// into <hidden service dir>/authorized_clients/, the downloading side puts the
+// private key into its ClientOnionAuthDir. Without the private key the onion
+// service descriptor can't be decrypted, so the service can't even be reached.
+
+const AUTHORIZED_CLIENT_FILE: &str = "torshare.auth";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientAuthKey([u8; 32]);
+
+impl ClientAuthKey {
+    pub fn generate() -> Self {
+        let mut secret = [0; 32];
+        thread_rng().fill(&mut secret);
+        ClientAuthKey(secret)
+    }
+
+    /// Base32 encoded public key, as used in authorized_clients/ and by ADD_ONION
+    pub fn public_key(&self) -> String {
+        BASE32_NOPAD.encode(PublicKey::from(&StaticSecret::from(self.0)).as_bytes())
+    }
+
+    /// Only lets clients with this key access the onion service in `hidden_service_dir`.
+    /// Needs to happen before tor starts, tor only reads the directory on startup.
+    pub fn authorize_client(&self, hidden_service_dir: &str) -> io::Result<()> {
+        let authorized_clients = Path::new(hidden_service_dir).join("authorized_clients");
+        create_private_dir(&authorized_clients)?;
+        fs::write(
+            authorized_clients.join(AUTHORIZED_CLIENT_FILE),
+            format!("descriptor:x25519:{}\n", self.public_key()),
+        )
+    }
+
+    /// Makes tor use this key for `hostname`, `client_auth_dir` has to be the
+    /// ClientOnionAuthDir of the tor we connect through.
+    pub fn write_auth_private(&self, client_auth_dir: &str, hostname: &str) -> io::Result<()> {
+        let service_id = hostname.trim_end_matches(".onion");
+        create_private_dir(Path::new(client_auth_dir))?;
+        fs::write(
+            Path::new(client_auth_dir).join(format!("{}.auth_private", service_id)),
+            format!("{}:descriptor:x25519:{}\n", service_id, self),
+        )
+    }
+}
+
+impl FromStr for ClientAuthKey {
+    type Err = &'static str;
+
+    /// Parses the base32 encoded private key, the format tor uses in .auth_private files
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let key = BASE32_NOPAD
+            .decode(key.to_ascii_uppercase().as_bytes())
+            .map_err(|_| "Client authorization key is not valid base32")?;
+        let key: [u8; 32] = key
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Client authorization key has to be 32 bytes long")?;
+        Ok(ClientAuthKey(key))
+    }
+}
+
+impl fmt::Display for ClientAuthKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", BASE32_NOPAD.encode(&self.0))
+    }
+}
+
+/// Removes the client we authorized in an earlier run, otherwise a reused hidden
+/// service directory would stay restricted to a key nobody knows anymore.
+pub fn remove_authorized_client(hidden_service_dir: &str) {
+    let _ = fs::remove_file(
+        Path::new(hidden_service_dir)
+            .join("authorized_clients")
+            .join(AUTHORIZED_CLIENT_FILE),
+    );
+}
+
+// Tor refuses to use key directories which are readable by others
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
@@ -0,0 +1,133 @@
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
+
+// Content-Disposition headers with file names as in RFC 6266: a plain ASCII
+// filename="..." for old clients and filename*=UTF-8''... (RFC 5987) with the
+// real name, which takes precedence when parsing.
+
+// attr-char of RFC 5987, everything else gets percent encoded
+const ATTR_CHAR: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+pub fn attachment(file_name: &str) -> String {
+    let ascii_file_name: String = file_name
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c } else { '_' })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii_file_name.replace('\\', "\\\\").replace('"', "\\\""),
+        utf8_percent_encode(file_name, ATTR_CHAR)
+    )
+}
+
+/// File name of a Content-Disposition header, preferring filename* over filename
+pub fn parse_file_name(header: &str) -> Option<String> {
+    let mut file_name = None;
+    let mut extended_file_name = None;
+    let (_, mut parameters) = header.split_once(';')?;
+    while let Some((name, rest)) = parameters.split_once('=') {
+        let rest = rest.trim_start();
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => parse_quoted_string(quoted)?,
+            None => {
+                let (value, rest) = rest.split_once(';').unwrap_or((rest, ""));
+                (value.trim_end().to_string(), rest)
+            }
+        };
+        // Skips parameters without a value
+        let name = name.rsplit(';').next().unwrap_or_default();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename" => file_name = Some(value),
+            "filename*" => extended_file_name = decode_extended_value(&value),
+            _ => {}
+        }
+        parameters = rest;
+    }
+    extended_file_name.or(file_name)
+}
+
+// Returns the unescaped content of a quoted string and what comes after the next ;
+fn parse_quoted_string(quoted: &str) -> Option<(String, &str)> {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => value.push(chars.next()?.1),
+            '"' => {
+                let rest = &quoted[i + 1..];
+                return Some((value, rest.split_once(';').map_or("", |(_, rest)| rest)));
+            }
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+// charset'language'percent-encoded-value
+fn decode_extended_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let decoded = percent_decode_str(encoded);
+    if charset.eq_ignore_ascii_case("UTF-8") {
+        decoded.decode_utf8().ok().map(|value| value.into_owned())
+    } else if charset.eq_ignore_ascii_case("ISO-8859-1") {
+        Some(decoded.map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_file_names() {
+        for file_name in &[
+            "plain.pdf",
+            "grandma's \"cheese\" cake.pdf",
+            "back\\slash; semi=colon.txt",
+            "100% legit.txt",
+            "Käsekuchen – Rezept 🍰.pdf",
+            "日本語のファイル.txt",
+        ] {
+            let header = attachment(file_name);
+            assert!(header.is_ascii(), "{}", header);
+            assert_eq!(parse_file_name(&header).as_deref(), Some(*file_name));
+        }
+    }
+
+    #[test]
+    fn prefers_extended_file_name() {
+        let header = "attachment; filename*=UTF-8''K%C3%A4se.txt; filename=\"K_se.txt\"";
+        assert_eq!(parse_file_name(header).as_deref(), Some("Käse.txt"));
+        let header = "attachment; filename=\"K_se.txt\"; FILENAME*=utf-8'de'K%C3%A4se.txt";
+        assert_eq!(parse_file_name(header).as_deref(), Some("Käse.txt"));
+    }
+
+    #[test]
+    fn decodes_iso_8859_1() {
+        let header = "attachment; filename*=iso-8859-1'en'%E4%F6%FC.txt";
+        assert_eq!(parse_file_name(header).as_deref(), Some("äöü.txt"));
+    }
+
+    #[test]
+    fn parses_plain_file_names() {
+        assert_eq!(parse_file_name("attachment; filename=plain.txt").as_deref(), Some("plain.txt"));
+        assert_eq!(parse_file_name("attachment; filename=\"a;b.txt\"; size=3").as_deref(), Some("a;b.txt"));
+        assert_eq!(parse_file_name("attachment; filename=\"a\\\"b.txt\"").as_deref(), Some("a\"b.txt"));
+        assert_eq!(parse_file_name("attachment; filename=\"unterminated"), None);
+        assert_eq!(parse_file_name("inline"), None);
+    }
+}
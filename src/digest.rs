@@ -0,0 +1,108 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::archive::folder_name;
+
+/// Name of the manifest route below a folder share, lists the sha-256 digest of
+/// every file in the shared folder in the format of `sha256sum`.
+pub const SHA256SUMS: &str = "sha256sums";
+/// Set on archive responses, points to the manifest of the shared folder
+/// relative to the share url.
+pub const SHA256SUMS_HEADER: &str = "X-Torshare-Sha256sums";
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    sha256_reader(&mut File::open(path)?)
+}
+
+fn sha256_reader<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Digest of every regular file in `folder`, named like the entries of the
+/// archive we stream for it. Symlinks are skipped, same as in the archive.
+pub fn folder_sha256sums(folder: &Path) -> io::Result<String> {
+    let mut sha256sums = Vec::new();
+    append_sha256sums(&mut sha256sums, folder, &folder_name(folder))?;
+    sha256sums.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(sha256sums
+        .into_iter()
+        .map(|(name, digest)| format!("{}  {}\n", digest, name))
+        .collect())
+}
+
+fn append_sha256sums(sha256sums: &mut Vec<(String, String)>, dir: &Path, archive_dir: &str) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let name = format!("{}/{}", archive_dir, entry.file_name().to_string_lossy());
+        if file_type.is_dir() {
+            append_sha256sums(sha256sums, &entry.path(), &name)?;
+        } else if file_type.is_file() {
+            sha256sums.push((name, sha256_file(&entry.path())?));
+        }
+    }
+    Ok(())
+}
+
+// Digest: sha-256=<base64>, see RFC 3230
+pub fn to_digest_header(sha256: &str) -> String {
+    format!("sha-256={}", base64::encode(hex::decode(sha256).unwrap_or_default()))
+}
+
+pub fn from_digest_header(digest_header: &str) -> Option<String> {
+    digest_header.split(',').find_map(|digest| {
+        let (algorithm, value) = digest.trim().split_once('=')?;
+        if !algorithm.eq_ignore_ascii_case("sha-256") {
+            return None;
+        }
+        base64::decode(value).ok().map(hex::encode)
+    })
+}
+
+/// Compares every regular file in the downloaded tar archive with the manifest
+/// of the shared folder. Returns the name of the first file that doesn't match.
+pub fn verify_tar(tar: &Path, sha256sums: &str) -> io::Result<Result<(), String>> {
+    let mut expected: HashMap<&str, &str> = sha256sums
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(digest, name)| (name, digest))
+        .collect();
+
+    let mut archive = tar::Archive::new(File::open(tar)?);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let digest = sha256_reader(&mut entry)?;
+        if expected.remove(name.as_str()) != Some(digest.as_str()) {
+            return Ok(Err(name));
+        }
+    }
+
+    // Files which are listed in the manifest but missing in the archive
+    if let Some(name) = expected.keys().next() {
+        return Ok(Err(name.to_string()));
+    }
+    Ok(Ok(()))
+}
@@ -1,16 +1,19 @@
 use reqwest;
 use std::{
-    fs::File,
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
     time::{Instant},
 };
 
 use std::io::Write;
-use std::{thread, time};
+use std::{sync::mpsc, thread, time};
 
-use crate::{tor_share_url::{TorShareUrl}, tor_utils::{TorDirOptions, TorDirectory, TorSocks5, start_tor_socks5}};
+use crate::{client_auth::ClientAuthKey, content_disposition::parse_file_name, digest::{SHA256SUMS_HEADER, from_digest_header, sha256_file, sha256_hex, verify_tar}, encryption::{Decryptor, ENCRYPTED_HEADER}, file_name::{safe_file_name, unique_file_name}, partial_download::PartialDownload, tor_share_url::{TorShareUrl}, tor_utils::{TorDirOptions, TorDirectory, TorSocks5, get_tor_bootstrap_progress, get_tor_error, start_tor_socks5}};
 use error_chain::error_chain;
-use reqwest::header::CONTENT_LENGTH;
+use reqwest::{StatusCode, header::{AsHeaderName, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, HeaderMap, IF_RANGE, LAST_MODIFIED, RANGE}};
 
+use sha2::{Digest, Sha256};
 use structopt::StructOpt;
 
 
@@ -18,8 +21,72 @@ use structopt::StructOpt;
 pub struct DownloadOptions {
     #[structopt(flatten)]
     pub tor_dir_options: TorDirOptions,
-    #[structopt(parse(try_from_str = TorShareUrl::from_str))]
+    /// Use an already running tor (e.g. 127.0.0.1:9050) instead of starting our own
+    #[structopt(long, env = "TOR_SOCKS_PROXY")]
+    pub socks_proxy: Option<TorSocks5>,
+    /// Client authorization key of a private share, if it isn't part of the url
+    #[structopt(long, env = "TOR_CLIENT_AUTH")]
+    pub client_auth: Option<ClientAuthKey>,
+    /// File or folder to save the download to, defaults to the current folder.
+    /// "-" writes the download to stdout.
+    #[structopt(short, long)]
+    pub output: Option<String>,
+    /// Replace a file which already exists under the same name
+    #[structopt(long, conflicts_with_all = &["rename", "fail"])]
+    pub overwrite: bool,
+    /// Save the download as "file (1).txt" if "file.txt" already exists
+    #[structopt(long, conflicts_with_all = &["overwrite", "fail"])]
+    pub rename: bool,
+    /// Stop if a file already exists under the same name (the default)
+    #[structopt(long, conflicts_with_all = &["overwrite", "rename"])]
+    pub fail: bool,
     pub url: TorShareUrl,
+    /// Same as --output, e.g. "-" to pipe the download into another command
+    #[structopt(name = "OUTPUT", conflicts_with = "output")]
+    pub output_path: Option<String>,
+}
+
+/// What happens if the downloaded file already exists
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Overwrite,
+    Rename,
+    Fail,
+}
+
+impl DownloadOptions {
+    pub fn to_stdout(&self) -> bool {
+        self.output().map_or(false, |output| output == "-")
+    }
+
+    fn output(&self) -> Option<&String> {
+        self.output.as_ref().or(self.output_path.as_ref())
+    }
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        if self.overwrite {
+            ConflictPolicy::Overwrite
+        } else if self.rename {
+            ConflictPolicy::Rename
+        } else {
+            ConflictPolicy::Fail
+        }
+    }
+
+    // Folder to download into and the file name, if --output names a file
+    fn destination(&self) -> (PathBuf, Option<String>) {
+        match self.output() {
+            None => (PathBuf::from("."), None),
+            Some(output) if output.ends_with('/') || Path::new(output).is_dir() => (PathBuf::from(output), None),
+            Some(output) => {
+                let output = Path::new(output);
+                let dir = output
+                    .parent()
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .unwrap_or_else(|| Path::new("."));
+                (dir.to_path_buf(), output.file_name().map(|name| name.to_string_lossy().into_owned()))
+            }
+        }
+    }
 }
 
 
@@ -35,15 +102,56 @@ pub struct DownloadProgress {
 }
 
 pub enum DownloadState<'a> {
+    ConnectingWithoutClientAuth,
     ConnectingWaitingForTor,
+    ConnectingBootstrappingTor(u8),
     ConnectingWaitingForProxy(&'a TorSocks5),
     ConnectedWaitingForPeer,
     ConnectedRetrievingFileInformation,
     ConnectedRetrievedFileInformation(&'a FileInformation),
     ConnectedDownloading(&'a FileInformation, DownloadProgress),
+    ConnectedVerifyingDigest,
+    ConnectedDigestVerified,
+    DisconnectedDigestMismatch(String),
     DisconnectedError(String)
 }
 
+// Where the download gets written to. Data on stdout can't be read again to
+// check its digest, so it gets hashed on the way.
+enum Destination {
+    File(File),
+    Stdout { stdout: io::Stdout, sha256: Sha256, size: u64 },
+}
+
+impl Write for Destination {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Destination::File(file) => file.write(buf),
+            Destination::Stdout { stdout, sha256, size } => {
+                let written = stdout.write(buf)?;
+                sha256.update(&buf[..written]);
+                *size += written as u64;
+                Ok(written)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Destination::File(file) => file.flush(),
+            Destination::Stdout { stdout, .. } => stdout.flush(),
+        }
+    }
+}
+
+enum Verification {
+    Verified,
+    // Sharing side didn't tell us a digest and neither did the url
+    Unverified,
+    Mismatch(String),
+    Failed(String),
+}
+
 
 error_chain! {
      foreign_links {
@@ -53,26 +161,136 @@ error_chain! {
          ToStrError(reqwest::header::ToStrError);
      }
 }
-pub async fn download_file(download_options: &DownloadOptions, cb: impl Fn(DownloadState)) {
-    
-    let tor_dir = TorDirectory::from_general_options(&download_options.tor_dir_options);
-    let tor_socks5 = TorSocks5::from_random_port();
+/// Our connection to the tor network, either our own tor in `tor_dir` or an
+/// already running one behind `tor_socks5`.
+pub struct TorClient {
+    pub tor_dir: Option<TorDirectory>,
+    pub tor_socks5: TorSocks5,
+}
+
+impl TorClient {
+    pub fn new(tor_dir_options: &TorDirOptions, socks_proxy: &Option<TorSocks5>) -> Self {
+        // Only start our own tor if we don't have one already running
+        let tor_dir = if socks_proxy.is_none() {
+            Some(TorDirectory::from_general_options(tor_dir_options))
+        } else {
+            None
+        };
+        let tor_socks5 = socks_proxy.clone().unwrap_or_else(TorSocks5::from_random_port);
+        TorClient { tor_dir, tor_socks5 }
+    }
+
+    /// Authorizes us for a private share with the key given on the command line or
+    /// else the one from the url. Returns false if we didn't because the key only
+    /// came with the url and the tor behind --socks-proxy may have it in its
+    /// ClientOnionAuthDir already.
+    pub fn authorize_for(&self, explicit: Option<&ClientAuthKey>, url: &TorShareUrl) -> std::result::Result<bool, String> {
+        match explicit.or(url.client_auth.as_ref()) {
+            Some(_) if self.tor_dir.is_none() && explicit.is_none() => Ok(false),
+            Some(client_auth) => self.authorize(client_auth, &url.hostname).map(|()| true),
+            None => Ok(true),
+        }
+    }
+
+    /// Private shares need the key in the ClientOnionAuthDir of the tor we connect
+    /// through, we can only put it there for our own one.
+    fn authorize(&self, client_auth: &ClientAuthKey, hostname: &str) -> std::result::Result<(), String> {
+        match &self.tor_dir {
+            Some(tor_dir) => client_auth
+                .write_auth_private(&tor_dir.client_auth_dir(), hostname)
+                .map_err(|e| format!("Couldn't write the client authorization key: {}", e)),
+            None => Err("Client authorization only works with the embedded tor, add the key to the ClientOnionAuthDir of your tor instead".into()),
+        }
+    }
+
+    /// Starts our own tor (if we have one) and waits until it's bootstrapped
+    pub fn start(&self, bootstrap_progress: impl Fn(u8)) -> std::result::Result<(), String> {
+        if let Some(tor_dir) = &self.tor_dir {
+            let torthread = start_tor_socks5(tor_dir, &self.tor_socks5);
+            // Tor only returns if it couldn't start, e.g. because the socks port is
+            // taken or another tor uses the data directory
+            let (tor_exited, tor_exit) = mpsc::channel();
+            thread::spawn(move || {
+                let _ = tor_exited.send(torthread.join());
+            });
+            loop {
+                if let Ok(exit) = tor_exit.try_recv() {
+                    return Err(match (get_tor_error(tor_dir), exit) {
+                        (Some(error), _) => format!("Tor exited: {}", error),
+                        (None, Ok(Ok(code))) => format!("Tor exited with code {}", code),
+                        (None, Ok(Err(e))) => format!("Tor couldn't start: {:?}", e),
+                        (None, Err(_)) => "Tor crashed".into(),
+                    });
+                }
+                let progress = get_tor_bootstrap_progress(tor_dir);
+                if progress >= 100 {
+                    break;
+                }
+                bootstrap_progress(progress);
+                thread::sleep(time::Duration::from_millis(250));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn http_client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .proxy(reqwest::Proxy::all(&self.tor_socks5.to_string()).unwrap())
+            .build().unwrap()
+    }
+
+    pub fn stop(self) {
+        if let Some(tor_dir) = self.tor_dir {
+            tor_dir.drop_if_temp();
+        }
+    }
+}
+
+/// Returns whether the download succeeded, what went wrong otherwise is reported to `cb`
+pub async fn download_file(download_options: &DownloadOptions, cb: impl Fn(DownloadState)) -> bool {
+    let tor_client = TorClient::new(&download_options.tor_dir_options, &download_options.socks_proxy);
     let tor_share_url = &download_options.url;
 
+    match tor_client.authorize_for(download_options.client_auth.as_ref(), tor_share_url) {
+        Ok(true) => {}
+        Ok(false) => cb(DownloadState::ConnectingWithoutClientAuth),
+        Err(e) => {
+            cb(DownloadState::DisconnectedError(e));
+            tor_client.stop();
+            return false;
+        }
+    }
+
     cb(DownloadState::ConnectingWaitingForTor);
-    let socks5_url = tor_socks5.to_string();
-    let client = reqwest::Client::builder()
-        .proxy(reqwest::Proxy::all(&socks5_url).unwrap())
-        .build().unwrap();
+    if let Err(e) = tor_client.start(|bootstrap_progress| cb(DownloadState::ConnectingBootstrappingTor(bootstrap_progress))) {
+        cb(DownloadState::DisconnectedError(e));
+        tor_client.stop();
+        return false;
+    }
+    let client = tor_client.http_client();
 
     let url = tor_share_url.to_url();
+    let (download_dir, output_name) = download_options.destination();
+    let download_dir = download_dir.as_path();
+    let conflict_policy = download_options.conflict_policy();
+    let to_stdout = download_options.to_stdout();
+    // If a previous run got interrupted, only ask for the missing bytes
+    let mut partial_download = PartialDownload::find(download_dir, &url)
+        .filter(|partial_download| !to_stdout && output_name.as_ref().map_or(true, |output_name| *output_name == partial_download.file_name));
+    let mut succeeded = false;
     loop {
-        let result = client.get(&url).send().await;
+        let mut request = client.get(&url);
+        if let Some(partial_download) = &partial_download {
+            request = request
+                .header(RANGE, format!("bytes={}-", partial_download.downloaded))
+                .header(IF_RANGE, partial_download.validator.as_str());
+        }
+        let result = request.send().await;
         if let Err(e) = result {
             //println!("{}\n", e);
             let socks5_unreachable = e.to_string().contains("Proxy server unreachable");
             if socks5_unreachable {
-                cb(DownloadState::ConnectingWaitingForProxy(&tor_socks5));
+                cb(DownloadState::ConnectingWaitingForProxy(&tor_client.tor_socks5));
                 thread::sleep(time::Duration::from_millis(50));
                 continue;
             } else {
@@ -84,41 +302,118 @@ pub async fn download_file(download_options: &DownloadOptions, cb: impl Fn(Downl
         cb(DownloadState::ConnectedRetrievingFileInformation);
 
         let mut result = result.unwrap();
-        let fname = if let Some(content_disposition) = result.headers().get("Content-Disposition") {
-            let content_disposition: String = content_disposition.to_str().unwrap().into();
-            if let Some(filename_index) = content_disposition.rfind("filename=\"") {
-                Some(
-                    content_disposition
-                        .chars()
-                        .skip(filename_index + 10)
-                        .take_while(|x| *x != '"')
-                        .collect(),
-                )
-            } else {
-                None
+        if result.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // Our partial data doesn't fit the shared file anymore, start over
+            if let Some(partial_download) = partial_download.take() {
+                partial_download.discard(download_dir);
+            }
+            continue;
+        }
+        if !result.status().is_success() {
+            // Whatever we got so far can't be completed anymore
+            if let Some(partial_download) = partial_download.take() {
+                partial_download.discard(download_dir);
+            }
+            let error = match result.status() {
+                StatusCode::GONE => "The share is no longer available".to_string(),
+                status => format!("Sharing side answered with {}", status),
+            };
+            cb(DownloadState::DisconnectedError(error));
+            break;
+        }
+        // The sharing side answers with the whole file if it changed in the meantime
+        let resumed_download = partial_download
+            .take()
+            .filter(|partial_download| {
+                result.status() == StatusCode::PARTIAL_CONTENT
+                    && content_range_start(result.headers()) == Some(partial_download.downloaded)
+            });
+
+        // Not necessarily ASCII, some servers send the file name as raw UTF-8
+        let fname = result
+            .headers()
+            .get(CONTENT_DISPOSITION)
+            .and_then(|content_disposition| parse_file_name(&String::from_utf8_lossy(content_disposition.as_bytes())));
+
+        // Encrypted shares carry their key in the url
+        let mut decryptor = if header_value(result.headers(), ENCRYPTED_HEADER).is_some() {
+            match &tor_share_url.key {
+                Some(key) => Some(Decryptor::new(key)),
+                None => {
+                    cb(DownloadState::DisconnectedError("The share is encrypted, but the url doesn't contain a key".into()));
+                    break;
+                }
             }
         } else {
             None
         };
 
-        let fname: String = fname.unwrap_or_else(|| format!("{}.file", tor_share_url.path));
-        let mut dest = File::create(&fname).unwrap();
+        let content_length = header_value(result.headers(), CONTENT_LENGTH)
+            .and_then(|content_length| content_length.parse::<u64>().ok())
+            .unwrap_or(0);
+        // Everything gets written to <file>.part first, the file itself only shows
+        // up once the download is complete and verified.
+        let (fname, dest, already_downloaded, file_size_in_bytes) = if to_stdout {
+            // Only used for the status lines
+            let fname = safe_file_name(&fname.unwrap_or_else(|| format!("{}.file", tor_share_url.path)), "download");
+            let dest = Destination::Stdout { stdout: io::stdout(), sha256: Sha256::new(), size: 0 };
+            (fname, Ok(dest), 0, content_length)
+        } else if let Some(resumed_download) = resumed_download {
+            let dest = OpenOptions::new()
+                .append(true)
+                .open(PartialDownload::part_file(download_dir, &resumed_download.file_name))
+                .map(Destination::File);
+            (resumed_download.file_name, dest, resumed_download.downloaded, resumed_download.size)
+        } else {
+            // Unless --output names the file, the name comes from the sharing side
+            // and must not lead out of download_dir
+            let fname = output_name.clone().unwrap_or_else(|| {
+                safe_file_name(&fname.unwrap_or_else(|| format!("{}.file", tor_share_url.path)), "download")
+            });
+            // Better to find out before downloading everything
+            let fname = match resolve_conflict(download_dir, &fname, conflict_policy) {
+                Ok(fname) => fname,
+                Err(e) => {
+                    cb(DownloadState::DisconnectedError(e));
+                    break;
+                }
+            };
+            let dest = File::create(PartialDownload::part_file(download_dir, &fname)).map(Destination::File);
+            (fname, dest, 0, content_length)
+        };
+        let mut dest = match dest {
+            Ok(dest) => dest,
+            Err(e) => {
+                cb(DownloadState::DisconnectedError(format!("Couldn't write {}.part: {}", fname, e)));
+                break;
+            }
+        };
+
+        // We can only resume later on if we know what we are downloading
+        let validator = header_value(result.headers(), ETAG).or_else(|| header_value(result.headers(), LAST_MODIFIED));
+        if let (Some(validator), true, false) = (&validator, file_size_in_bytes > 0, to_stdout) {
+            let _ = PartialDownload::save(download_dir, &fname, &url, file_size_in_bytes, validator);
+        }
 
-        let file_size: f64 = result
-            .headers()
-            .get(CONTENT_LENGTH)
-            .ok_or("0").unwrap()
-            .to_str().unwrap()
-            .to_string()
-            .parse::<f64>()
-            .unwrap()
-            / 1000000.0;
+        let file_size: f64 = file_size_in_bytes as f64 / 1000000.0;
+
+        let digest = header_value(result.headers(), "Digest").and_then(|digest| from_digest_header(&digest));
+        let sha256sums = header_value(result.headers(), SHA256SUMS_HEADER);
+        // Get the manifest of a folder share before its archive, the sharing side
+        // stops right after the archive went through with --once or --max-downloads
+        let manifest = match (&sha256sums, to_stdout) {
+            (Some(sha256sums), false) => Some(fetch_sha256sums(&client, tor_share_url, sha256sums).await),
+            _ => None,
+        };
+        let is_tar = header_value(result.headers(), CONTENT_TYPE).map_or(false, |content_type| content_type == "application/x-tar");
 
         let file_information = FileInformation { name: fname, size: file_size };
 
         cb(DownloadState::ConnectedRetrievedFileInformation(&file_information));
 
-        let mut downloaded_megabytes: f64 = 0.0;
+        let mut downloaded_megabytes: f64 = already_downloaded as f64 / 1000000.0;
+        let mut downloaded_bytes = already_downloaded;
+        let mut completed = false;
         let mut last_write = Instant::now();
         // bytes per second
         let mut speed: f64 = -1.0;
@@ -131,10 +426,35 @@ pub async fn download_file(download_options: &DownloadOptions, cb: impl Fn(Downl
             }
             let chunk = chunk.unwrap();
             if chunk.is_none() {
+                completed = file_size_in_bytes == 0 || downloaded_bytes == file_size_in_bytes;
+                if !completed {
+                    cb(DownloadState::DisconnectedError("Connection closed before the download completed".into()));
+                } else if let Some(decryptor) = decryptor.take() {
+                    // Only the last frame tells us that nothing got cut off
+                    let written = decryptor
+                        .finish()
+                        .map_err(|e| e.to_string())
+                        .and_then(|plaintext| dest.write_all(&plaintext).map_err(|e| e.to_string()));
+                    if let Err(e) = written {
+                        completed = false;
+                        cb(DownloadState::DisconnectedError(e));
+                    }
+                }
                 break
             }
             let chunk = chunk.unwrap();
-            dest.write(&chunk);
+            let written = match &mut decryptor {
+                Some(decryptor) => decryptor
+                    .update(&chunk)
+                    .map_err(|e| e.to_string())
+                    .and_then(|plaintext| dest.write_all(&plaintext).map_err(|e| e.to_string())),
+                None => dest.write_all(&chunk).map_err(|e| e.to_string()),
+            };
+            if let Err(e) = written {
+                cb(DownloadState::DisconnectedError(e));
+                break
+            }
+            downloaded_bytes += chunk.len() as u64;
             let elapsed_time_as_secs = last_write.elapsed().as_secs_f64();
 
             downloaded_bytes_last_second = downloaded_bytes_last_second + chunk.len();
@@ -157,7 +477,170 @@ pub async fn download_file(download_options: &DownloadOptions, cb: impl Fn(Downl
                 cb(DownloadState::ConnectedDownloading(&file_information, DownloadProgress { downloaded_megabytes, percent, speed}));
             }
         }
-        println!("\n");
+        if completed {
+            if let Destination::Stdout { mut stdout, sha256, size } = dest {
+                cb(DownloadState::ConnectedVerifyingDigest);
+                let verification = match stdout.flush() {
+                    Ok(()) => verify_streamed_digest(tor_share_url, &hex::encode(sha256.finalize()), size, digest, sha256sums),
+                    Err(e) => Verification::Failed(e.to_string()),
+                };
+                match verification {
+                    Verification::Verified => {
+                        cb(DownloadState::ConnectedDigestVerified);
+                        succeeded = true;
+                    }
+                    Verification::Unverified => succeeded = true,
+                    Verification::Mismatch(reason) => cb(DownloadState::DisconnectedDigestMismatch(reason)),
+                    Verification::Failed(err) => cb(DownloadState::DisconnectedError(err)),
+                }
+                break;
+            }
+            PartialDownload::remove_state(download_dir, &file_information.name);
+
+            let part_file = PartialDownload::part_file(download_dir, &file_information.name);
+            cb(DownloadState::ConnectedVerifyingDigest);
+            let verification = verify_digest(tor_share_url, &part_file, digest, manifest, is_tar);
+            if let Verification::Mismatch(reason) = verification {
+                // Keep the file around for inspection, but make sure nobody
+                // mistakes it for the real thing.
+                let quarantined = download_dir.join(format!("{}.corrupted", file_information.name));
+                let _ = fs::rename(&part_file, &quarantined);
+                cb(DownloadState::DisconnectedDigestMismatch(format!("{}, moved the download to {}", reason, quarantined.display())));
+                break;
+            }
+            // Another file could have taken the name while we were downloading
+            let renamed = resolve_conflict(download_dir, &file_information.name, conflict_policy)
+                .and_then(|fname| fs::rename(&part_file, download_dir.join(fname)).map_err(|e| e.to_string()));
+            if let Err(e) = renamed {
+                cb(DownloadState::DisconnectedError(format!("{}, the download is in {}", e, part_file.display())));
+                break;
+            }
+            match verification {
+                Verification::Verified => {
+                    cb(DownloadState::ConnectedDigestVerified);
+                    succeeded = true;
+                }
+                Verification::Failed(err) => cb(DownloadState::DisconnectedError(format!("Couldn't verify the download: {}", err))),
+                Verification::Unverified => succeeded = true,
+                Verification::Mismatch(_) => {}
+            }
+        }
+        if !to_stdout {
+            println!("\n");
+        }
         break;
     }
+    tor_client.stop();
+    succeeded
+}
+
+// verify_digest for downloads to stdout, `sha256` is the digest of what we wrote
+fn verify_streamed_digest(tor_share_url: &TorShareUrl, sha256: &str, size: u64, digest: Option<String>, sha256sums: Option<String>) -> Verification {
+    if let Some(expected) = tor_share_url.size {
+        if size != expected {
+            return Verification::Mismatch(format!("Download has {} bytes instead of {}", size, expected));
+        }
+    }
+    // Every file of a folder share is only known after unpacking its archive
+    if sha256sums.is_some() {
+        return Verification::Unverified;
+    }
+    match tor_share_url.digest.clone().or(digest) {
+        Some(expected) if expected == sha256 => Verification::Verified,
+        Some(_) => Verification::Mismatch("sha-256 digest doesn't match".into()),
+        None => Verification::Unverified,
+    }
+}
+
+// Name under which a download called `file_name` ends up in `dir`
+fn resolve_conflict(dir: &Path, file_name: &str, conflict_policy: ConflictPolicy) -> std::result::Result<String, String> {
+    if !dir.join(file_name).exists() {
+        return Ok(file_name.into());
+    }
+    match conflict_policy {
+        ConflictPolicy::Overwrite => Ok(file_name.into()),
+        ConflictPolicy::Rename => Ok(unique_file_name(dir, file_name)),
+        ConflictPolicy::Fail => Err(format!(
+            "{} already exists, use --overwrite or --rename",
+            dir.join(file_name).display()
+        )),
+    }
+}
+
+// `manifest` is the sha256sums of a folder share together with its digest
+fn verify_digest(tor_share_url: &TorShareUrl, file: &Path, digest: Option<String>, manifest: Option<Result<(String, Option<String>)>>, is_tar: bool) -> Verification {
+    // torshare:// links tell us the size as well, a cheap check before hashing
+    if let Some(expected) = tor_share_url.size {
+        match file.metadata() {
+            Ok(metadata) if metadata.len() != expected => {
+                return Verification::Mismatch(format!("Download has {} bytes instead of {}", metadata.len(), expected))
+            }
+            Ok(_) => {}
+            Err(err) => return Verification::Failed(err.to_string()),
+        }
+    }
+    if let Some(manifest) = manifest {
+        // Folder share, first check the manifest and then every file in the archive against it
+        let (sha256sums, manifest_digest) = match manifest {
+            Ok(manifest) => manifest,
+            Err(err) => return Verification::Failed(err.to_string()),
+        };
+        if let Some(expected) = tor_share_url.digest.clone().or(manifest_digest) {
+            if sha256_hex(sha256sums.as_bytes()) != expected {
+                return Verification::Mismatch("Manifest of the shared folder doesn't match its sha-256 digest".into());
+            }
+        }
+        if !is_tar {
+            return Verification::Unverified;
+        }
+        return match verify_tar(file, &sha256sums) {
+            Ok(Ok(())) => Verification::Verified,
+            Ok(Err(name)) => Verification::Mismatch(format!("sha-256 digest of {} doesn't match", name)),
+            Err(err) => Verification::Failed(err.to_string()),
+        };
+    }
+
+    let expected = match tor_share_url.digest.clone().or(digest) {
+        Some(expected) => expected,
+        None => return Verification::Unverified,
+    };
+    match sha256_file(file) {
+        Ok(actual) if actual == expected => Verification::Verified,
+        Ok(_) => Verification::Mismatch("sha-256 digest doesn't match".into()),
+        Err(err) => Verification::Failed(err.to_string()),
+    }
+}
+
+async fn fetch_sha256sums(client: &reqwest::Client, tor_share_url: &TorShareUrl, sha256sums: &str) -> Result<(String, Option<String>)> {
+    let response = client
+        .get(&format!("{}/{}", tor_share_url.to_url(), sha256sums))
+        .send()
+        .await?
+        .error_for_status()?;
+    let digest = header_value(response.headers(), "Digest").and_then(|digest| from_digest_header(&digest));
+    let encrypted = header_value(response.headers(), ENCRYPTED_HEADER).is_some();
+    let body = response.bytes().await?;
+    let sha256sums = match (&tor_share_url.key, encrypted) {
+        (Some(key), true) => {
+            let mut decryptor = Decryptor::new(key);
+            let mut sha256sums = decryptor.update(&body).map_err(|e| e.to_string())?;
+            sha256sums.extend(decryptor.finish().map_err(|e| e.to_string())?);
+            sha256sums
+        }
+        (None, true) => return Err("The share is encrypted, but the url doesn't contain a key".into()),
+        (_, false) => body.to_vec(),
+    };
+    Ok((String::from_utf8_lossy(&sha256sums).into_owned(), digest))
+}
+
+fn header_value<K: AsHeaderName>(headers: &HeaderMap, name: K) -> Option<String> {
+    headers.get(name)?.to_str().ok().map(|value| value.to_string())
+}
+
+// Content-Range: bytes 100-999/1000
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let content_range = header_value(headers, CONTENT_RANGE)?;
+    let (_, range) = content_range.split_once("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.parse().ok()
 }
@@ -0,0 +1,285 @@
+use std::{convert::TryInto, fmt, io, str::FromStr};
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use futures::{Stream, StreamExt};
+use rand::{thread_rng, Rng};
+
+// Encrypted shares are sent as a header followed by frames of encrypted chunks:
+//
+//   MAGIC | nonce prefix (19 bytes) | frame | frame | ... | final frame
+//
+// Every frame is a chunk of up to CHUNK_SIZE plaintext bytes sealed with
+// XChaCha20-Poly1305. The nonce of a frame is the random prefix, a 32 bit big
+// endian counter and a flag which is 1 for the last frame (STREAM construction),
+// so reordered, dropped or truncated frames fail to decrypt.
+
+/// Set on encrypted responses, so the downloader knows it has to decrypt.
+pub const ENCRYPTED_HEADER: &str = "X-Torshare-Encrypted";
+pub const ENCRYPTION: &str = "xchacha20poly1305-stream";
+
+const MAGIC: &[u8] = b"TSE1";
+const NONCE_PREFIX_SIZE: usize = 19;
+const HEADER_SIZE: usize = MAGIC.len() + NONCE_PREFIX_SIZE;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const FRAME_SIZE: usize = CHUNK_SIZE + TAG_SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        thread_rng().fill(&mut key);
+        EncryptionKey(key)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.0))
+    }
+}
+
+impl FromStr for EncryptionKey {
+    type Err = &'static str;
+
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        let key = base64::decode_config(key, base64::URL_SAFE_NO_PAD).map_err(|_| "Key is not valid base64")?;
+        let key: [u8; 32] = key.as_slice().try_into().map_err(|_| "Key has to be 32 bytes long")?;
+        Ok(EncryptionKey(key))
+    }
+}
+
+impl fmt::Display for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", base64::encode_config(&self.0, base64::URL_SAFE_NO_PAD))
+    }
+}
+
+fn nonce(prefix: &[u8], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0; 24];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+    *XNonce::from_slice(&nonce)
+}
+
+/// Size of the encrypted stream for `size` bytes of plaintext. There is always
+/// at least the (possibly empty) final frame.
+pub fn encrypted_size(size: u64) -> u64 {
+    let frames = std::cmp::max(1, (size + CHUNK_SIZE as u64 - 1) / CHUNK_SIZE as u64);
+    HEADER_SIZE as u64 + size + frames * TAG_SIZE as u64
+}
+
+struct Encryptor<S> {
+    plaintext: S,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buffer: Vec<u8>,
+    header_sent: bool,
+    plaintext_done: bool,
+    finished: bool,
+}
+
+pub fn encrypt_stream<S>(key: &EncryptionKey, plaintext: S) -> impl Stream<Item = io::Result<Vec<u8>>>
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+    let mut nonce_prefix = [0; NONCE_PREFIX_SIZE];
+    thread_rng().fill(&mut nonce_prefix);
+    let encryptor = Encryptor {
+        plaintext,
+        cipher: key.cipher(),
+        nonce_prefix,
+        counter: 0,
+        buffer: Vec::new(),
+        header_sent: false,
+        plaintext_done: false,
+        finished: false,
+    };
+
+    futures::stream::try_unfold(encryptor, |mut encryptor| async move {
+        if encryptor.finished {
+            return Ok(None);
+        }
+        if !encryptor.header_sent {
+            encryptor.header_sent = true;
+            let header = [MAGIC, &encryptor.nonce_prefix[..]].concat();
+            return Ok(Some((header, encryptor)));
+        }
+
+        // We need to know whether more data follows to mark the last frame
+        while !encryptor.plaintext_done && encryptor.buffer.len() <= CHUNK_SIZE {
+            match encryptor.plaintext.next().await {
+                Some(chunk) => encryptor.buffer.extend_from_slice(&chunk?),
+                None => encryptor.plaintext_done = true,
+            }
+        }
+
+        let last = encryptor.buffer.len() <= CHUNK_SIZE;
+        let chunk: Vec<u8> = if last {
+            std::mem::take(&mut encryptor.buffer)
+        } else {
+            encryptor.buffer.drain(..CHUNK_SIZE).collect()
+        };
+        let frame = encryptor
+            .cipher
+            .encrypt(&nonce(&encryptor.nonce_prefix, encryptor.counter, last), chunk.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Couldn't encrypt chunk"))?;
+        encryptor.counter = encryptor
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Shared data is too big to encrypt"))?;
+        encryptor.finished = last;
+        Ok(Some((frame, encryptor)))
+    })
+}
+
+pub fn encrypt(key: &EncryptionKey, plaintext: Vec<u8>) -> impl Stream<Item = io::Result<Vec<u8>>> {
+    encrypt_stream(key, futures::stream::iter(vec![Ok(plaintext)]))
+}
+
+#[derive(Debug)]
+pub struct DecryptionError;
+
+impl std::fmt::Display for DecryptionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Couldn't decrypt the download, the key is wrong or the data got modified")
+    }
+}
+
+/// Counterpart of `encrypt_stream`, gets fed the encrypted stream chunk by chunk.
+pub struct Decryptor {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: Option<Vec<u8>>,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl Decryptor {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Decryptor { cipher: key.cipher(), nonce_prefix: None, counter: 0, buffer: Vec::new() }
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        self.buffer.extend_from_slice(data);
+        if self.nonce_prefix.is_none() {
+            if self.buffer.len() < HEADER_SIZE {
+                return Ok(Vec::new());
+            }
+            if &self.buffer[..MAGIC.len()] != MAGIC {
+                return Err(DecryptionError);
+            }
+            self.nonce_prefix = Some(self.buffer[MAGIC.len()..HEADER_SIZE].to_vec());
+            self.buffer.drain(..HEADER_SIZE);
+        }
+
+        // Only decrypt a frame if more follows, the last one gets handled by finish
+        let mut plaintext = Vec::new();
+        while self.buffer.len() > FRAME_SIZE {
+            let frame: Vec<u8> = self.buffer.drain(..FRAME_SIZE).collect();
+            plaintext.extend(self.decrypt_frame(&frame, false)?);
+        }
+        Ok(plaintext)
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>, DecryptionError> {
+        if self.nonce_prefix.is_none() {
+            return Err(DecryptionError);
+        }
+        let frame = std::mem::take(&mut self.buffer);
+        self.decrypt_frame(&frame, true)
+    }
+
+    fn decrypt_frame(&mut self, frame: &[u8], last: bool) -> Result<Vec<u8>, DecryptionError> {
+        let nonce_prefix = self.nonce_prefix.as_ref().ok_or(DecryptionError)?;
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce(nonce_prefix, self.counter, last), frame)
+            .map_err(|_| DecryptionError)?;
+        self.counter = self.counter.checked_add(1).ok_or(DecryptionError)?;
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, TryStreamExt};
+
+    const SIZES: &[usize] = &[0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 2 * CHUNK_SIZE, 2 * CHUNK_SIZE + 1];
+
+    fn plaintext(size: usize) -> Vec<u8> {
+        (0..size).map(|i| i as u8).collect()
+    }
+
+    // Header and frames as the share sends them
+    fn encrypt_parts(key: &EncryptionKey, plaintext: Vec<u8>) -> Vec<Vec<u8>> {
+        block_on(encrypt(key, plaintext).try_collect()).unwrap()
+    }
+
+    // Feeds the decryptor in pieces which don't line up with the frames
+    fn decrypt(key: &EncryptionKey, ciphertext: &[u8]) -> Result<Vec<u8>, DecryptionError> {
+        let mut decryptor = Decryptor::new(key);
+        let mut plaintext = Vec::new();
+        for piece in ciphertext.chunks(7777) {
+            plaintext.extend(decryptor.update(piece)?);
+        }
+        plaintext.extend(decryptor.finish()?);
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn round_trips_across_chunk_boundaries() {
+        let key = EncryptionKey::generate();
+        for &size in SIZES {
+            let ciphertext = encrypt_parts(&key, plaintext(size)).concat();
+            assert_eq!(decrypt(&key, &ciphertext).unwrap(), plaintext(size), "{} bytes", size);
+        }
+    }
+
+    #[test]
+    fn encrypts_to_encrypted_size() {
+        let key = EncryptionKey::generate();
+        for &size in SIZES {
+            let ciphertext = encrypt_parts(&key, plaintext(size)).concat();
+            assert_eq!(ciphertext.len() as u64, encrypted_size(size as u64), "{} bytes", size);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let key = EncryptionKey::generate();
+        let ciphertext = encrypt_parts(&key, plaintext(2 * CHUNK_SIZE + 1)).concat();
+        for &length in &[0, HEADER_SIZE - 1, HEADER_SIZE, HEADER_SIZE + FRAME_SIZE, ciphertext.len() - 1] {
+            assert!(decrypt(&key, &ciphertext[..length]).is_err(), "{} bytes", length);
+        }
+    }
+
+    #[test]
+    fn rejects_dropped_final_frame() {
+        let key = EncryptionKey::generate();
+        let mut parts = encrypt_parts(&key, plaintext(2 * CHUNK_SIZE));
+        assert_eq!(parts.len(), 3);
+        parts.pop();
+        assert!(decrypt(&key, &parts.concat()).is_err());
+    }
+
+    #[test]
+    fn rejects_reordered_frames() {
+        let key = EncryptionKey::generate();
+        let mut parts = encrypt_parts(&key, plaintext(2 * CHUNK_SIZE + 1));
+        assert_eq!(parts.len(), 4);
+        parts.swap(1, 2);
+        assert!(decrypt(&key, &parts.concat()).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_key() {
+        let ciphertext = encrypt_parts(&EncryptionKey::generate(), plaintext(CHUNK_SIZE + 1)).concat();
+        assert!(decrypt(&EncryptionKey::generate(), &ciphertext).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use crate::partial_download::{PART_FILE_EXTENSION, STATE_FILE_EXTENSION};
+
+// File names the other side sends us (Content-Disposition of a share, names of
+// uploads) end up in File::create, so they must not be able to point anywhere
+// else than into the download folder.
+
+// Leaves room for " (1)", ".torshare" and the like within the 255 bytes most
+// file systems allow
+const MAX_FILE_NAME_LENGTH: usize = 200;
+// Longer extensions get cut off together with the rest of the name
+const MAX_EXTENSION_LENGTH: usize = 16;
+// Names Windows maps to devices, with any extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Only keeps the last path component of `name` and drops everything which could
+/// make it a hidden file or a path. Names with control or invisible characters
+/// or reserved names get replaced by `fallback` as a whole. Names which would
+/// pass for our own .part or .torshare files get a trailing "_".
+pub fn safe_file_name(name: &str, fallback: &str) -> String {
+    let name = name.rsplit(|c| c == '/' || c == '\\').next().unwrap_or_default();
+    // Windows drops trailing dots and spaces itself, leading dots hide the file
+    let name = name.trim().trim_start_matches('.').trim_end_matches(|c| c == '.' || c == ' ');
+    if name.is_empty() || name.chars().any(|c| c.is_control() || is_invisible(c)) || is_reserved(name) {
+        return fallback.into();
+    }
+    // Not allowed in file names on Windows, ':' would even create an alternate data stream
+    let name: String = name
+        .chars()
+        .map(|c| if "<>:\"|?*".contains(c) { '_' } else { c })
+        .collect();
+    let mut name = truncate(&name);
+    if is_partial_download_file(&name) {
+        name.push('_');
+    }
+    name
+}
+
+/// "file.txt" becomes "file (1).txt", "file (2).txt" and so on
+pub fn numbered_file_name(name: &str, number: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{} ({}).{}", stem, number, extension),
+        _ => format!("{} ({})", name, number),
+    }
+}
+
+/// First of `name`, "name (1)", "name (2)", ... which doesn't exist in `dir` yet
+pub fn unique_file_name(dir: &Path, name: &str) -> String {
+    (0..)
+        .map(|number| if number == 0 { name.to_string() } else { numbered_file_name(name, number) })
+        .find(|candidate| !dir.join(candidate).exists())
+        .expect("there is always a free file name")
+}
+
+// Unicode format characters (category Cf) and line separators. Bidi overrides
+// turn "invoice\u{202e}fdp.exe" into what looks like "invoiceexe.pdf", in the
+// file manager as well as in our status line.
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{ad}'
+            | '\u{600}'..='\u{605}'
+            | '\u{61c}'
+            | '\u{6dd}'
+            | '\u{70f}'
+            | '\u{180e}'
+            | '\u{200b}'..='\u{200f}'
+            | '\u{2028}'..='\u{202e}'
+            | '\u{2060}'..='\u{206f}'
+            | '\u{feff}'
+            | '\u{fff9}'..='\u{fffb}'
+            | '\u{110bd}'
+            | '\u{1d173}'..='\u{1d17a}'
+            | '\u{e0001}'
+            | '\u{e0020}'..='\u{e007f}'
+    )
+}
+
+// Names of the files we keep next to an unfinished download, see partial_download.rs
+fn is_partial_download_file(name: &str) -> bool {
+    match name.rsplit_once('.') {
+        Some((_, extension)) => {
+            extension.eq_ignore_ascii_case(PART_FILE_EXTENSION) || extension.eq_ignore_ascii_case(STATE_FILE_EXTENSION)
+        }
+        None => false,
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+// Cuts the name to MAX_FILE_NAME_LENGTH bytes, but keeps the extension
+fn truncate(name: &str) -> String {
+    if name.len() <= MAX_FILE_NAME_LENGTH {
+        return name.into();
+    }
+    let extension = match name.rsplit_once('.') {
+        Some((_, extension)) if extension.len() < MAX_EXTENSION_LENGTH => &name[name.len() - extension.len() - 1..],
+        _ => "",
+    };
+    let mut end = MAX_FILE_NAME_LENGTH - extension.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &name[..end], extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FALLBACK: &str = "download";
+
+    fn safe(name: &str) -> String {
+        safe_file_name(name, FALLBACK)
+    }
+
+    #[test]
+    fn strips_paths() {
+        assert_eq!(safe("../../.bashrc"), "bashrc");
+        assert_eq!(safe("/etc/passwd"), "passwd");
+        assert_eq!(safe("C:\\x\\y"), "y");
+        assert_eq!(safe("dir/"), FALLBACK);
+        assert_eq!(safe(".."), FALLBACK);
+        assert_eq!(safe("..."), FALLBACK);
+        assert_eq!(safe(""), FALLBACK);
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        assert_eq!(safe("CON"), FALLBACK);
+        assert_eq!(safe("CON.txt"), FALLBACK);
+        assert_eq!(safe("nul .tar.gz"), FALLBACK);
+        assert_eq!(safe("CONSOLE.txt"), "CONSOLE.txt");
+        assert_eq!(safe("COM10"), "COM10");
+    }
+
+    #[test]
+    fn rejects_control_and_invisible_characters() {
+        assert_eq!(safe("a\nb.txt"), FALLBACK);
+        assert_eq!(safe("\x1b[31mred.txt"), FALLBACK);
+        assert_eq!(safe("invoice\u{202e}fdp.exe"), FALLBACK);
+        assert_eq!(safe("invoice\u{2066}.pdf"), FALLBACK);
+        assert_eq!(safe("zero\u{200b}width.txt"), FALLBACK);
+        assert_eq!(safe("Käsekuchen – Rezept 🍰.pdf"), "Käsekuchen – Rezept 🍰.pdf");
+    }
+
+    #[test]
+    fn escapes_partial_download_files() {
+        assert_eq!(safe("x.torshare"), "x.torshare_");
+        assert_eq!(safe("x.PART"), "x.PART_");
+        assert_eq!(safe("x.part1.rar"), "x.part1.rar");
+        assert_eq!(safe("torshare"), "torshare");
+    }
+
+    #[test]
+    fn replaces_characters_windows_does_not_allow() {
+        assert_eq!(safe("a:b?.txt"), "a_b_.txt");
+        assert_eq!(safe("  name. . "), "name");
+    }
+
+    #[test]
+    fn truncates_long_names_but_keeps_the_extension() {
+        let name = safe(&format!("{}.txt", "x".repeat(296)));
+        assert_eq!(name.len(), MAX_FILE_NAME_LENGTH);
+        assert!(name.ends_with("x.txt"));
+
+        // Without an extension worth keeping everything gets cut
+        let name = safe(&format!("{}.{}", "x".repeat(200), "y".repeat(100)));
+        assert_eq!(name.len(), MAX_FILE_NAME_LENGTH);
+    }
+
+    #[test]
+    fn truncates_at_character_boundaries() {
+        // 196 bytes for the name would cut an 'ä' in half
+        let name = safe(&format!("x{}.txt", "ä".repeat(150)));
+        assert_eq!(name.len(), MAX_FILE_NAME_LENGTH - 1);
+        assert!(name.starts_with("xä"));
+        assert!(name.ends_with("ä.txt"));
+    }
+}
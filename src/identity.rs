@@ -0,0 +1,146 @@
+use std::{
+    convert::TryInto,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+use rand::{thread_rng, Rng};
+
+use crate::{client_auth::create_private_dir, onion_address::onion_address};
+
+// Named onion service identities, so a share keeps its .onion hostname between
+// runs. Every identity is a hidden service directory as tor writes it, below
+// <data dir>/torshare/identities/<name>.
+
+const SECRET_KEY_FILE: &str = "hs_ed25519_secret_key";
+const PUBLIC_KEY_FILE: &str = "hs_ed25519_public_key";
+const HOSTNAME_FILE: &str = "hostname";
+// Headers of the key files, followed by the 64 bytes of the expanded secret key
+// or the 32 bytes of the public key
+const SECRET_KEY_HEADER: &[u8; 32] = b"== ed25519v1-secret: type0 ==\0\0\0";
+const PUBLIC_KEY_HEADER: &[u8; 32] = b"== ed25519v1-public: type0 ==\0\0\0";
+
+/// Expanded ed25519 secret key of an onion service, the way tor stores it
+pub struct IdentityKey([u8; 64]);
+
+impl IdentityKey {
+    pub fn generate() -> Self {
+        let mut seed = [0; 32];
+        thread_rng().fill(&mut seed);
+        Self::from_seed(&seed)
+    }
+
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let secret_key = SecretKey::from_bytes(seed).expect("32 bytes are a valid secret key");
+        IdentityKey(ExpandedSecretKey::from(&secret_key).to_bytes())
+    }
+
+    /// Parses the contents of a hs_ed25519_secret_key file
+    pub fn from_file_contents(contents: &[u8]) -> io::Result<Self> {
+        contents
+            .strip_prefix(&SECRET_KEY_HEADER[..])
+            .and_then(|secret_key| secret_key.try_into().ok())
+            .map(IdentityKey)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Not an ed25519 onion service key"))
+    }
+
+    pub fn to_file_contents(&self) -> Vec<u8> {
+        [&SECRET_KEY_HEADER[..], &self.0].concat()
+    }
+
+    /// Parses the key blob of ADD_ONION ED25519-V3:<key>
+    pub fn from_base64(secret_key: &str) -> io::Result<Self> {
+        base64::decode(secret_key)
+            .ok()
+            .and_then(|secret_key| secret_key.as_slice().try_into().ok())
+            .map(IdentityKey)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "Not an ed25519 onion service key"))
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(&self.0[..])
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        // from_bytes only fails for keys which aren't 64 bytes long
+        let expanded = ExpandedSecretKey::from_bytes(&self.0).expect("expanded secret key has 64 bytes");
+        PublicKey::from(&expanded).to_bytes()
+    }
+
+    pub fn hostname(&self) -> String {
+        onion_address(&self.public_key())
+    }
+}
+
+pub fn identities_dir() -> Result<PathBuf, String> {
+    dirs::data_dir()
+        .map(|data_dir| data_dir.join("torshare").join("identities"))
+        .ok_or_else(|| "Couldn't find a data directory for identities".into())
+}
+
+/// Hidden service directory of the identity `name`, doesn't need to exist
+pub fn identity_path(name: &str) -> Result<PathBuf, String> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(format!("Identity name {:?} may only contain letters, digits, - and _", name));
+    }
+    Ok(identities_dir()?.join(name))
+}
+
+/// Hidden service directory of the identity `name`, gets created if it doesn't exist yet.
+pub fn identity_dir(name: &str) -> Result<PathBuf, String> {
+    let identity_dir = identity_path(name)?;
+    create_private_dir(&identity_dir).map_err(|e| format!("Couldn't create {}: {}", identity_dir.display(), e))?;
+    Ok(identity_dir)
+}
+
+/// Names of all identities with their hostname, if they got a key already
+pub fn list_identities() -> Result<Vec<(String, Option<String>)>, String> {
+    let entries = match fs::read_dir(identities_dir()?) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut identities: Vec<_> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| {
+            let hostname = read_secret_key(&entry.path()).ok().flatten().map(|key| key.hostname());
+            (entry.file_name().to_string_lossy().into_owned(), hostname)
+        })
+        .collect();
+    identities.sort();
+    Ok(identities)
+}
+
+/// Reads the secret key of the onion service in `hidden_service_dir`
+pub fn read_secret_key(hidden_service_dir: &Path) -> io::Result<Option<IdentityKey>> {
+    match fs::read(hidden_service_dir.join(SECRET_KEY_FILE)) {
+        Ok(contents) => IdentityKey::from_file_contents(&contents).map(Some),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the key files and hostname of an onion service into `hidden_service_dir`,
+/// the same way tor does for its hidden service directories.
+pub fn write_secret_key(hidden_service_dir: &Path, key: &IdentityKey) -> io::Result<()> {
+    write_private_file(&hidden_service_dir.join(SECRET_KEY_FILE), &key.to_file_contents())?;
+    write_private_file(
+        &hidden_service_dir.join(PUBLIC_KEY_FILE),
+        &[&PUBLIC_KEY_HEADER[..], &key.public_key()].concat(),
+    )?;
+    write_private_file(&hidden_service_dir.join(HOSTNAME_FILE), format!("{}\n", key.hostname()).as_bytes())
+}
+
+pub fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
@@ -0,0 +1,191 @@
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{thread_rng, Rng};
+use structopt::StructOpt;
+
+use crate::identity::{IdentityKey, identity_dir, identity_path, list_identities, read_secret_key, write_private_file, write_secret_key};
+
+#[derive(Debug, StructOpt)]
+pub enum KeysCommand {
+    /// List all identities with their .onion hostname
+    List,
+    /// Create a new identity
+    Create {
+        name: String,
+    },
+    /// Remove an identity. Its .onion hostname is gone for good, unless it got exported before.
+    Remove {
+        name: String,
+    },
+    /// Export the key of an identity, e.g. as a backup or to move it to another machine
+    Export {
+        name: String,
+        file: String,
+    },
+    /// Import a key from `torshare keys export` or the hs_ed25519_secret_key of a tor hidden service
+    Import {
+        name: String,
+        file: String,
+    },
+    /// Create an identity whose .onion hostname starts with `prefix`. Every further
+    /// character takes about 32 times longer to find.
+    Vanity {
+        /// Letters a-z and digits 2-7
+        prefix: String,
+        /// Name of the identity, defaults to the prefix
+        #[structopt(long)]
+        name: Option<String>,
+    },
+}
+
+// How often a running vanity search reports its progress
+const VANITY_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs the command and returns the lines to print. Long running commands report
+/// their progress through `progress` in the meantime.
+pub fn run_keys_command(keys_command: &KeysCommand, progress: impl Fn(String)) -> Result<Vec<String>, String> {
+    match keys_command {
+        KeysCommand::List => {
+            let identities = list_identities()?;
+            if identities.is_empty() {
+                return Ok(vec!["No identities yet, create one with \"torshare keys create <name>\"".into()]);
+            }
+            Ok(identities
+                .into_iter()
+                .map(|(name, hostname)| format!("{}: {}", name, hostname.unwrap_or_else(|| "no key".into())))
+                .collect())
+        }
+        KeysCommand::Create { name } => {
+            let key = IdentityKey::generate();
+            store_new_identity(name, &key)?;
+            Ok(vec![format!("Created identity {} with hostname {}", name, key.hostname())])
+        }
+        KeysCommand::Remove { name } => {
+            let identity = identity_path(name)?;
+            if !identity.is_dir() {
+                return Err(format!("There is no identity {}", name));
+            }
+            fs::remove_dir_all(&identity).map_err(|e| format!("Couldn't remove {}: {}", identity.display(), e))?;
+            Ok(vec![format!("Removed identity {}", name)])
+        }
+        KeysCommand::Export { name, file } => {
+            let key = read_secret_key(&identity_path(name)?)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("There is no key for identity {}", name))?;
+            write_private_file(file.as_ref(), &key.to_file_contents()).map_err(|e| format!("Couldn't write {}: {}", file, e))?;
+            Ok(vec![format!("Exported identity {} ({}) to {}", name, key.hostname(), file)])
+        }
+        KeysCommand::Import { name, file } => {
+            let contents = fs::read(file).map_err(|e| format!("Couldn't read {}: {}", file, e))?;
+            let key = IdentityKey::from_file_contents(&contents).map_err(|e| format!("{}: {}", file, e))?;
+            store_new_identity(name, &key)?;
+            Ok(vec![format!("Imported identity {} with hostname {}", name, key.hostname())])
+        }
+        KeysCommand::Vanity { prefix, name } => {
+            let prefix = prefix.to_ascii_lowercase();
+            if !prefix.chars().all(|c| matches!(c, 'a'..='z' | '2'..='7')) {
+                return Err(format!("{} can't be part of a hostname, only a-z and 2-7 can", prefix));
+            }
+            // Only 52 characters are made of the public key, the rest is checksum and version
+            if prefix.len() > 52 {
+                return Err("The prefix can be at most 52 characters long".into());
+            }
+            let name = name.as_ref().unwrap_or(&prefix);
+            // Fail before searching for hours, not after
+            let identity = identity_dir(name)?;
+            if read_secret_key(&identity).map_err(|e| e.to_string())?.is_some() {
+                return Err(format!("Identity {} already exists, remove it first", name));
+            }
+
+            let started = Instant::now();
+            let (key, tries) = find_vanity_key(&prefix, |tries| {
+                let seconds = started.elapsed().as_secs_f64();
+                progress(format!(
+                    "Searching for {}... tried {} of about {} keys ({:.0} keys/s)",
+                    prefix,
+                    tries,
+                    32u64.saturating_pow(prefix.len() as u32),
+                    tries as f64 / seconds
+                ))
+            });
+            store_new_identity(name, &key)?;
+            Ok(vec![format!(
+                "Created identity {} with hostname {} after {} keys in {:.0}s",
+                name,
+                key.hostname(),
+                tries,
+                started.elapsed().as_secs_f64()
+            )])
+        }
+    }
+}
+
+// Tries random keys on every core until one of them has a hostname starting with
+// `prefix`. Returns the key and the number of keys tried.
+fn find_vanity_key(prefix: &str, progress: impl Fn(u64)) -> (IdentityKey, u64) {
+    let found = Arc::new(AtomicBool::new(false));
+    let tries = Arc::new(AtomicU64::new(0));
+    let (key_sender, keys) = mpsc::channel();
+
+    let workers: Vec<_> = (0..num_cpus::get())
+        .map(|_| {
+            let prefix = prefix.to_string();
+            let found = found.clone();
+            let tries = tries.clone();
+            let key_sender = key_sender.clone();
+            thread::spawn(move || {
+                let mut seed = [0; 32];
+                thread_rng().fill(&mut seed);
+                while !found.load(Ordering::Relaxed) {
+                    // Counting up the seed is a lot cheaper than getting new randomness
+                    // and just as good, the public key is derived from its hash.
+                    increment(&mut seed);
+                    let key = IdentityKey::from_seed(&seed);
+                    tries.fetch_add(1, Ordering::Relaxed);
+                    if key.hostname().starts_with(&prefix) {
+                        found.store(true, Ordering::Relaxed);
+                        let _ = key_sender.send(key);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let key = loop {
+        match keys.recv_timeout(VANITY_PROGRESS_INTERVAL) {
+            Ok(key) => break key,
+            Err(_) => progress(tries.load(Ordering::Relaxed)),
+        }
+    };
+    for worker in workers {
+        let _ = worker.join();
+    }
+    (key, tries.load(Ordering::Relaxed))
+}
+
+fn increment(seed: &mut [u8; 32]) {
+    for byte in seed.iter_mut() {
+        let (incremented, overflow) = byte.overflowing_add(1);
+        *byte = incremented;
+        if !overflow {
+            break;
+        }
+    }
+}
+
+// Never overwrites the key of an existing identity, it would be lost otherwise
+fn store_new_identity(name: &str, key: &IdentityKey) -> Result<(), String> {
+    let identity = identity_dir(name)?;
+    if read_secret_key(&identity).map_err(|e| e.to_string())?.is_some() {
+        return Err(format!("Identity {} already exists, remove it first", name));
+    }
+    write_secret_key(&identity, key).map_err(|e| format!("Couldn't write the key of {}: {}", name, e))
+}
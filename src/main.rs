@@ -3,7 +3,7 @@
 extern crate tempdir;
 
 mod cli;
-use cli::{CliOptions, Color, print_status_line, save_cursor_position};
+use cli::{CliOptions, Color, print_status_line, print_status_to_stderr, save_cursor_position};
 use structopt::StructOpt;
 
 mod tor_utils;
@@ -11,21 +11,69 @@ use tor_utils::{TorDirectory, TorHiddenServiceConfig, TorSocks5, get_hidden_serv
 
 mod tor_share_url;
 
+mod tor_control;
+
+mod client_auth;
+
+mod onion_address;
+
+mod word_code;
+
+mod identity;
+
+mod keys;
+use keys::{KeysCommand, run_keys_command};
+
+mod archive;
+
+mod digest;
+
+mod encryption;
+
+mod serve_file;
+
+mod file_name;
+
+mod content_disposition;
+
 mod share;
 use share::{ShareOptions, ShareState, share_file};
 
+mod partial_download;
+
 mod download_file;
 use download_file::{DownloadOptions, DownloadState, download_file};
 
-async fn download(download_options: &DownloadOptions) {
+mod receive;
+use receive::{ReceiveOptions, ReceiveState, receive_files};
+
+mod upload_file;
+use upload_file::{UploadOptions, UploadState, upload_file};
+
+async fn download(download_options: &DownloadOptions) -> bool {
+    if download_options.to_stdout() {
+        print_status_to_stderr();
+    }
 
     //dbg!("Ready!");
     download_file(&download_options, |download_state| {
         save_cursor_position();
         match download_state {
+            DownloadState::ConnectingWithoutClientAuth => {
+                print_status_line(
+                    &Color::Yellow,
+                    "The url contains a client authorization key, --socks-proxy only works if it is in the ClientOnionAuthDir of your tor",
+                );
+            },
             DownloadState::ConnectingWaitingForTor => {
                 print_status_line(&Color::Yellow, "Connecting to tor network...");
             },
+            DownloadState::ConnectingBootstrappingTor(bootstrap_progress) => {
+                print_status_line(
+                    &Color::Yellow,
+                    format!("Connecting to tor network... Bootstrapped {}%", bootstrap_progress),
+                );
+            },
             DownloadState::ConnectingWaitingForProxy(tor_socks5) => {
                 print_status_line(
                     &Color::Yellow,
@@ -66,6 +114,21 @@ async fn download(download_options: &DownloadOptions) {
                     ),
                 );
             }
+            DownloadState::ConnectedVerifyingDigest => {
+                print_status_line(&Color::Green, "Verifying sha-256 digest...");
+            }
+            DownloadState::ConnectedDigestVerified => {
+                print_status_line(&Color::Green, "sha-256 digest verified");
+            }
+            DownloadState::DisconnectedDigestMismatch ( error ) => {
+                print_status_line(
+                    &Color::Red,
+                    format!(
+                        "Error: {}",
+                        error
+                    ),
+                );
+            }
             DownloadState::DisconnectedError ( error ) => {
                 print_status_line(
                     &Color::Red,
@@ -77,7 +140,7 @@ async fn download(download_options: &DownloadOptions) {
 
             }
         };
-    }).await;
+    }).await
 }
 
 async fn share(share_options: &ShareOptions) {
@@ -87,19 +150,57 @@ async fn share(share_options: &ShareOptions) {
             ShareState::ConnectingStartingTor => {
                 print_status_line(&Color::Yellow, "Starting Tor");
 
+            },
+            ShareState::ConnectingComputingDigest => {
+                print_status_line(&Color::Yellow, "Computing sha-256 digest...");
+
             },
             ShareState::OnlineSharingNow(tor_share_url) => {
                 print_status_line(
                     &Color::Green,
                     format!(
                         "Sharing now! Run following command to download: \"torshare download {}\"",
-                        tor_share_url.to_string()
+                        tor_share_url.to_torshare_uri()
                     ),
                 );
+                if let Some(word_code) = tor_share_url.to_word_code().filter(|_| share_options.words) {
+                    // The client authorization key isn't part of the words
+                    let client_auth = tor_share_url
+                        .client_auth
+                        .as_ref()
+                        .map(|client_auth| format!(" --client-auth {}", client_auth))
+                        .unwrap_or_default();
+                    print_status_line(&Color::Green, format!("Or as words: \"torshare download {}{}\"", word_code, client_auth));
+                }
+                if let Some(client_auth) = &tor_share_url.client_auth {
+                    print_status_line(
+                        &Color::Green,
+                        format!(
+                            "Only reachable with the client authorization key {} (included in the url above)",
+                            client_auth
+                        ),
+                    );
+                }
+            },
+            ShareState::OnlineDownloadCompleted(downloads) => {
+                print_status_line(
+                    &Color::Green,
+                    format!("Download completed ({} completed, {} aborted)", downloads.completed, downloads.aborted),
+                );
+            },
+            ShareState::OnlineDownloadAborted(downloads) => {
+                print_status_line(
+                    &Color::Yellow,
+                    format!("Download aborted ({} completed, {} aborted)", downloads.completed, downloads.aborted),
+                );
             },
             ShareState::OfflineStopped => {
                 print_status_line(&Color::Red, "Stopped sharing\n");
 
+            },
+            ShareState::OfflineExpired => {
+                print_status_line(&Color::Red, "Share expired, stopped sharing\n");
+
             },
             ShareState::OfflineError(err) => {
                 print_status_line(&Color::Red, format!("Error: {}\n", err));
@@ -109,17 +210,123 @@ async fn share(share_options: &ShareOptions) {
     }).await;
 }
 
+async fn receive(receive_options: &ReceiveOptions) {
+    save_cursor_position();
+    receive_files(&receive_options, |receive_state| {
+        match receive_state {
+            ReceiveState::ConnectingStartingTor => {
+                print_status_line(&Color::Yellow, "Starting Tor");
+            },
+            ReceiveState::OnlineReceivingNow(tor_share_url) => {
+                print_status_line(
+                    &Color::Green,
+                    format!(
+                        "Receiving now! Open {} in the tor browser or run following command to upload: \"torshare upload {} <file>\"",
+                        tor_share_url.to_url(),
+                        tor_share_url.to_string()
+                    ),
+                );
+            },
+            ReceiveState::ReceivedFile(received_file) => {
+                print_status_line(
+                    &Color::Green,
+                    format!("Received {} ({:.3}mb)", received_file.name, received_file.size as f64 / 1000000.0),
+                );
+            },
+            ReceiveState::RejectedUpload(reason) => {
+                print_status_line(&Color::Yellow, format!("Rejected upload: {}", reason));
+            },
+            ReceiveState::OfflineStopped => {
+                print_status_line(&Color::Red, "Stopped receiving\n");
+            },
+            ReceiveState::OfflineError(err) => {
+                print_status_line(&Color::Red, format!("Error: {}\n", err));
+            }
+        }
+    }).await;
+}
+
+async fn upload(upload_options: &UploadOptions) {
+    upload_file(&upload_options, |upload_state| {
+        save_cursor_position();
+        match upload_state {
+            UploadState::ConnectingWithoutClientAuth => {
+                print_status_line(
+                    &Color::Yellow,
+                    "The url contains a client authorization key, --socks-proxy only works if it is in the ClientOnionAuthDir of your tor",
+                );
+            },
+            UploadState::ConnectingWaitingForTor => {
+                print_status_line(&Color::Yellow, "Connecting to tor network...");
+            },
+            UploadState::ConnectingBootstrappingTor(bootstrap_progress) => {
+                print_status_line(
+                    &Color::Yellow,
+                    format!("Connecting to tor network... Bootstrapped {}%", bootstrap_progress),
+                );
+            },
+            UploadState::ConnectingWaitingForProxy(tor_socks5) => {
+                print_status_line(
+                    &Color::Yellow,
+                    format!("Connecting to tor network... Waiting for proxy... {}", tor_socks5.port),
+                );
+            }
+            UploadState::ConnectedWaitingForPeer => {
+                print_status_line(&Color::Yellow, "Waiting for receiving side to come online...");
+            }
+            UploadState::ConnectedUploading(file_information, upload_progress) => {
+                print_status_line(
+                    &Color::Green,
+                    format!(
+                        "{}: {:.3}mb of {:.3}mb {:.1}% {:.3}mb/s",
+                        file_information.name, upload_progress.uploaded_megabytes, file_information.size, upload_progress.percent, upload_progress.speed
+                    ),
+                );
+            }
+            UploadState::ConnectedUploaded(file_information) => {
+                print_status_line(&Color::Green, format!("Uploaded {}", file_information.name));
+            }
+            UploadState::DisconnectedError(error) => {
+                print_status_line(&Color::Red, format!("Error: {}", error));
+            }
+        };
+    }).await;
+}
+
+fn keys(keys_command: &KeysCommand) {
+    match run_keys_command(keys_command, |progress| print_status_line(&Color::Yellow, progress)) {
+        Ok(lines) => {
+            for line in lines {
+                print_status_line(&Color::Green, line);
+            }
+        }
+        Err(err) => print_status_line(&Color::Red, format!("Error: {}", err)),
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let options: CliOptions = CliOptions::from_args();
 
     match &options {
         CliOptions::Download { download_options} => {
-            download(&download_options).await;
+            // Scripts piping the download somewhere need to know it failed
+            if !download(&download_options).await {
+                std::process::exit(1);
+            }
         }
         CliOptions::Share { share_options } => {
             share(&share_options).await;
         }
+        CliOptions::Receive { receive_options } => {
+            receive(&receive_options).await;
+        }
+        CliOptions::Upload { upload_options } => {
+            upload(&upload_options).await;
+        }
+        CliOptions::Keys { keys_command } => {
+            keys(&keys_command);
+        }
     }
 
 
@@ -0,0 +1,127 @@
+use std::{convert::TryInto, fmt};
+
+use data_encoding::BASE32_NOPAD;
+use sha3::{Digest, Sha3_256};
+
+// v3 onion addresses are base32(public key | checksum | version), see section 6
+// of rend-spec-v3.txt
+
+const VERSION: u8 = 3;
+// base32 of 32 bytes public key, 2 bytes checksum and the version byte
+const ADDRESS_LENGTH: usize = 56;
+
+#[derive(Debug)]
+pub enum OnionAddressError {
+    NotOnion,
+    WrongLength(usize),
+    NotBase32,
+    WrongVersion(u8),
+    WrongChecksum,
+}
+
+impl fmt::Display for OnionAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnionAddressError::NotOnion => write!(f, "Hostname doesn't end with .onion"),
+            OnionAddressError::WrongLength(length) => write!(
+                f,
+                "Onion address has {} characters instead of {}, is it complete?",
+                length, ADDRESS_LENGTH
+            ),
+            OnionAddressError::NotBase32 => write!(f, "Onion address may only contain the letters a-z and digits 2-7"),
+            OnionAddressError::WrongVersion(version) => {
+                write!(f, "Onion address has version {}, only version {} is supported", version, VERSION)
+            }
+            OnionAddressError::WrongChecksum => write!(f, "Onion address has a wrong checksum, it's probably mistyped"),
+        }
+    }
+}
+
+/// Hostname of the v3 onion service with the ed25519 `public_key`
+pub fn onion_address(public_key: &[u8; 32]) -> String {
+    let address = [&public_key[..], &checksum(public_key), &[VERSION]].concat();
+    format!("{}.onion", BASE32_NOPAD.encode(&address).to_ascii_lowercase())
+}
+
+/// Checks that `hostname` is a valid v3 onion address and returns its public key
+pub fn parse_onion_address(hostname: &str) -> Result<[u8; 32], OnionAddressError> {
+    let address = hostname.strip_suffix(".onion").ok_or(OnionAddressError::NotOnion)?;
+    if address.len() != ADDRESS_LENGTH {
+        return Err(OnionAddressError::WrongLength(address.len()));
+    }
+    let address = BASE32_NOPAD
+        .decode(address.to_ascii_uppercase().as_bytes())
+        .map_err(|_| OnionAddressError::NotBase32)?;
+    let (public_key, checksum_and_version) = address.split_at(32);
+    if checksum_and_version[2] != VERSION {
+        return Err(OnionAddressError::WrongVersion(checksum_and_version[2]));
+    }
+    if checksum_and_version[..2] != checksum(public_key) {
+        return Err(OnionAddressError::WrongChecksum);
+    }
+    Ok(public_key.try_into().expect("split at 32 bytes"))
+}
+
+fn checksum(public_key: &[u8]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(public_key);
+    hasher.update(&[VERSION]);
+    let checksum = hasher.finalize();
+    [checksum[0], checksum[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "jksjjmaxtyny437liml3zc4rfr3a6ugkptdbm46h6iv4cmbp7o4yu6ad.onion";
+
+    fn replace_char(address: &str, index: usize, c: char) -> String {
+        let mut address: Vec<char> = address.chars().collect();
+        address[index] = c;
+        address.into_iter().collect()
+    }
+
+    #[test]
+    fn round_trips_public_keys() {
+        let public_key = parse_onion_address(ADDRESS).unwrap();
+        assert_eq!(onion_address(&public_key), ADDRESS);
+        assert_eq!(parse_onion_address(&format!("{}.onion", ADDRESS.trim_end_matches(".onion").to_uppercase())).unwrap(), public_key);
+    }
+
+    #[test]
+    fn rejects_typos() {
+        for &index in &[0, 10, 51] {
+            let typo = replace_char(ADDRESS, index, if ADDRESS.as_bytes()[index] == b'a' { 'b' } else { 'a' });
+            assert!(matches!(parse_onion_address(&typo), Err(OnionAddressError::WrongChecksum)), "{}", typo);
+        }
+    }
+
+    #[test]
+    fn rejects_v2_addresses() {
+        assert!(matches!(parse_onion_address("expyuzz4wqqyqhjn.onion"), Err(OnionAddressError::WrongLength(16))));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let public_key = [7; 32];
+        let address = [&public_key[..], &checksum(&public_key), &[4]].concat();
+        let address = format!("{}.onion", BASE32_NOPAD.encode(&address).to_ascii_lowercase());
+        assert!(matches!(parse_onion_address(&address), Err(OnionAddressError::WrongVersion(4))));
+    }
+
+    #[test]
+    fn rejects_characters_outside_base32() {
+        for &c in &['1', '8', '-', 'é'] {
+            let address = replace_char(ADDRESS, 5, c);
+            assert!(parse_onion_address(&address).is_err(), "{}", address);
+        }
+        assert!(matches!(parse_onion_address(&replace_char(ADDRESS, 5, '1')), Err(OnionAddressError::NotBase32)));
+    }
+
+    #[test]
+    fn rejects_other_hostnames() {
+        assert!(matches!(parse_onion_address("example.com"), Err(OnionAddressError::NotOnion)));
+    }
+}
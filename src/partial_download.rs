@@ -0,0 +1,90 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::file_name::safe_file_name;
+
+pub const STATE_FILE_EXTENSION: &str = "torshare";
+pub const PART_FILE_EXTENSION: &str = "part";
+
+/// A download which got interrupted. Its data is in `<file>.part` and next to it
+/// we keep a small `<file>.torshare` state file which tells us where the data
+/// came from and which version of the shared file it belongs to.
+#[derive(Debug)]
+pub struct PartialDownload {
+    pub file_name: String,
+    /// Only hostname and path, the fragment of a share url can hold keys and
+    /// the same share can be opened in other forms like torshare:// or words.
+    pub url: String,
+    pub size: u64,
+    /// ETag or Last-Modified of the shared file, send back as If-Range
+    pub validator: String,
+    pub downloaded: u64,
+}
+
+impl PartialDownload {
+    pub fn find(dir: &Path, url: &str) -> Option<Self> {
+        fs::read_dir(dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |extension| extension == STATE_FILE_EXTENSION))
+            .filter_map(|state_file| Self::load(dir, &state_file))
+            .find(|partial_download| partial_download.url == url)
+    }
+
+    fn load(dir: &Path, state_file: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(state_file).ok()?;
+        let value = |key: &str| {
+            contents
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+                .map(|value| value.to_string())
+        };
+
+        let file_name = value("file")?;
+        // Only trust state files we could have written ourselves, about the file
+        // right next to them
+        if file_name != safe_file_name(&file_name, "download") || state_file != Self::state_file(dir, &file_name) {
+            return None;
+        }
+        let downloaded = fs::metadata(Self::part_file(dir, &file_name)).ok()?.len();
+        let size = value("size")?.parse().ok()?;
+        if downloaded > size {
+            return None;
+        }
+        Some(PartialDownload {
+            file_name,
+            url: value("url")?,
+            size,
+            validator: value("validator")?,
+            downloaded,
+        })
+    }
+
+    pub fn save(dir: &Path, file_name: &str, url: &str, size: u64, validator: &str) -> io::Result<()> {
+        let mut state_file = File::create(Self::state_file(dir, file_name))?;
+        write!(state_file, "file={}\nurl={}\nsize={}\nvalidator={}\n", file_name, url, size, validator)
+    }
+
+    pub fn remove_state(dir: &Path, file_name: &str) {
+        let _ = fs::remove_file(Self::state_file(dir, file_name));
+    }
+
+    /// Throws away the partial data, e.g. because the shared file changed.
+    pub fn discard(self, dir: &Path) {
+        let _ = fs::remove_file(Self::part_file(dir, &self.file_name));
+        Self::remove_state(dir, &self.file_name);
+    }
+
+    /// Where the data of a download goes until it is complete and verified
+    pub fn part_file(dir: &Path, file_name: &str) -> PathBuf {
+        dir.join(format!("{}.{}", file_name, PART_FILE_EXTENSION))
+    }
+
+    fn state_file(dir: &Path, file_name: &str) -> PathBuf {
+        dir.join(format!("{}.{}", file_name, STATE_FILE_EXTENSION))
+    }
+}
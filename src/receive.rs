@@ -0,0 +1,229 @@
+use std::{convert::Infallible, io, path::{Path, PathBuf}};
+
+use async_ctrlc::CtrlC;
+use futures::{Future, Stream, StreamExt, channel::mpsc::{UnboundedSender, unbounded}};
+use futures_lite::future::FutureExt;
+use hyper::{Body, StatusCode, body::Buf};
+use percent_encoding::percent_decode_str;
+use structopt::StructOpt;
+use tokio::{fs::{self, File, OpenOptions}, io::AsyncWriteExt};
+use warp::{Filter, multipart::FormData};
+
+use crate::{file_name::{numbered_file_name, safe_file_name}, tor_share_url::TorShareUrl, tor_utils::{TorDirOptions, TorDirectory, TorHiddenServiceConfig, get_hidden_service_hostname, start_tor_hidden_service}};
+
+#[derive(Debug, StructOpt)]
+pub struct ReceiveOptions {
+    #[structopt(flatten)]
+    pub tor_dir_options: TorDirOptions,
+
+    /// Folder to put received files into
+    pub folder: String,
+
+    /// Maximum size of a single upload in megabytes
+    #[structopt(long, default_value = "1000")]
+    pub max_size: u64,
+}
+
+pub struct ReceivedFile {
+    pub name: String,
+    pub size: u64,
+}
+
+pub enum ReceiveState<'a> {
+    ConnectingStartingTor,
+    OnlineReceivingNow(&'a TorShareUrl),
+    ReceivedFile(&'a ReceivedFile),
+    RejectedUpload(String),
+    OfflineStopped,
+    OfflineError(String)
+}
+
+// What the webserver tells share_file about uploads
+enum Upload {
+    Received(ReceivedFile),
+    Rejected(String),
+}
+
+const UPLOAD_FORM: &str = r#"<!DOCTYPE html>
+<html>
+  <head><meta charset="utf-8"><title>TorShare</title></head>
+  <body>
+    <form method="post" enctype="multipart/form-data">
+      <input type="file" name="file" multiple>
+      <input type="submit" value="Upload">
+    </form>
+  </body>
+</html>
+"#;
+
+pub async fn receive_files(receive_options: &ReceiveOptions, cb: impl Fn(ReceiveState)) {
+    let folder = PathBuf::from(&receive_options.folder);
+    if !folder.is_dir() {
+        cb(ReceiveState::OfflineError(format!("{} is not a folder", receive_options.folder)));
+        return;
+    }
+
+    let hidden_service_config = TorHiddenServiceConfig::from_random_port();
+    cb(ReceiveState::ConnectingStartingTor);
+    let tor_dir = TorDirectory::from_general_options(&receive_options.tor_dir_options);
+    let _torthread = start_tor_hidden_service(&tor_dir, &hidden_service_config);
+    let hostname = get_hidden_service_hostname(&tor_dir).unwrap_or("Error".to_string());
+    let tor_share_url = TorShareUrl::random_path(hostname);
+
+    let (upload_sender, mut uploads) = unbounded();
+    let receive = start_webserver(&hidden_service_config, folder, tor_share_url.path.clone(), receive_options.max_size * 1000000, upload_sender);
+    let report_uploads = async {
+        while let Some(upload) = uploads.next().await {
+            match upload {
+                Upload::Received(file) => cb(ReceiveState::ReceivedFile(&file)),
+                Upload::Rejected(reason) => cb(ReceiveState::RejectedUpload(reason)),
+            }
+        }
+    };
+
+    let ctrlc = CtrlC::new().expect("cannot create Ctrl+C handler?");
+    cb(ReceiveState::OnlineReceivingNow(&tor_share_url));
+
+    ctrlc.race(receive).race(report_uploads).await;
+    tor_dir.drop_if_temp();
+
+    cb(ReceiveState::OfflineStopped);
+}
+
+fn start_webserver(tor_hidden_service_config: &TorHiddenServiceConfig, folder: PathBuf, id: String, max_size: u64, uploads: UnboundedSender<Upload>) -> impl Future<Output = ()> {
+    // GET /{id} => upload form
+    let form = warp::path(id.clone())
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(|| warp::reply::html(UPLOAD_FORM));
+
+    // POST /{id} => multipart/form-data from the upload form
+    let form_folder = folder.clone();
+    let form_uploads = uploads.clone();
+    let multipart = warp::path(id.clone())
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::multipart::form().max_length(max_size))
+        .and_then(move |form: FormData| receive_form(form_folder.clone(), form, max_size, form_uploads.clone()));
+
+    // PUT /{id}/{file name} => raw file in the body, what `torshare upload` sends
+    let put = warp::path(id)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(warp::body::stream())
+        .and_then(move |name: String, body| {
+            let name = percent_decode_str(&name).decode_utf8_lossy().into_owned();
+            let chunks = body.map(|chunk: Result<_, warp::Error>| chunk.map(|chunk| chunk.bytes().to_vec()).map_err(|e| e.to_string()));
+            receive_file(folder.clone(), name, Box::pin(chunks), max_size, uploads.clone())
+        });
+
+    let routes = form.or(multipart).or(put);
+
+    println!("Starting http server on port {}", tor_hidden_service_config.local_port);
+
+    warp::serve(routes).run(([127, 0, 0, 1], tor_hidden_service_config.local_port))
+}
+
+async fn receive_form(folder: PathBuf, mut form: FormData, max_size: u64, uploads: UnboundedSender<Upload>) -> Result<warp::reply::Response, Infallible> {
+    let mut received = Vec::new();
+    while let Some(part) = form.next().await {
+        let part = match part {
+            Ok(part) => part,
+            Err(e) => return Ok(reject(StatusCode::BAD_REQUEST, e.to_string(), &uploads)),
+        };
+        // Skip everything that isn't a file, like the submit button
+        let name = match part.filename() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let chunks = futures::stream::unfold(part, |mut part| async move {
+            let chunk = part.data().await?;
+            Some((chunk.map(|chunk| chunk.bytes().to_vec()).map_err(|e| e.to_string()), part))
+        });
+        match save_upload(&folder, &name, Box::pin(chunks), max_size).await {
+            Ok(file) => {
+                received.push(file.name.clone());
+                let _ = uploads.unbounded_send(Upload::Received(file));
+            }
+            Err((status, reason)) => return Ok(reject(status, reason, &uploads)),
+        }
+    }
+    Ok(text_response(StatusCode::CREATED, format!("Received {}\n", received.join(", "))))
+}
+
+async fn receive_file<S>(folder: PathBuf, name: String, chunks: S, max_size: u64, uploads: UnboundedSender<Upload>) -> Result<warp::reply::Response, Infallible>
+where
+    S: Stream<Item = Result<Vec<u8>, String>> + Unpin,
+{
+    match save_upload(&folder, &name, chunks, max_size).await {
+        Ok(file) => {
+            let response = text_response(StatusCode::CREATED, format!("Received {}\n", file.name));
+            let _ = uploads.unbounded_send(Upload::Received(file));
+            Ok(response)
+        }
+        Err((status, reason)) => Ok(reject(status, reason, &uploads)),
+    }
+}
+
+// Streams the upload into a new file in `folder`. Incomplete or too big uploads
+// get removed again.
+async fn save_upload<S>(folder: &Path, name: &str, mut chunks: S, max_size: u64) -> Result<ReceivedFile, (StatusCode, String)>
+where
+    S: Stream<Item = Result<Vec<u8>, String>> + Unpin,
+{
+    let internal_error = |e: io::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let (mut file, name) = create_unique_file(folder, &safe_file_name(name, "upload")).await.map_err(internal_error)?;
+    let path = folder.join(&name);
+
+    let mut size = 0;
+    let mut written = Ok(());
+    while let Some(chunk) = chunks.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                written = Err((StatusCode::BAD_REQUEST, format!("Upload of {} got interrupted: {}", name, e)));
+                break;
+            }
+        };
+        size += chunk.len() as u64;
+        if size > max_size {
+            written = Err((StatusCode::PAYLOAD_TOO_LARGE, format!("Upload of {} is bigger than {} bytes", name, max_size)));
+            break;
+        }
+        if let Err(e) = file.write_all(&chunk).await {
+            written = Err(internal_error(e));
+            break;
+        }
+    }
+    if let Err(e) = written.and(file.flush().await.map_err(internal_error)) {
+        drop(file);
+        let _ = fs::remove_file(&path).await;
+        return Err(e);
+    }
+    Ok(ReceivedFile { name, size })
+}
+
+// Never overwrites anything, "file.txt" becomes "file (1).txt" if it already exists
+async fn create_unique_file(folder: &Path, name: &str) -> io::Result<(File, String)> {
+    for i in 0.. {
+        let candidate = if i == 0 { name.to_string() } else { numbered_file_name(name, i) };
+        match OpenOptions::new().write(true).create_new(true).open(folder.join(&candidate)).await {
+            Ok(file) => return Ok((file, candidate)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+fn reject(status: StatusCode, reason: String, uploads: &UnboundedSender<Upload>) -> warp::reply::Response {
+    let _ = uploads.unbounded_send(Upload::Rejected(reason.clone()));
+    text_response(status, format!("{}\n", reason))
+}
+
+fn text_response(status: StatusCode, text: String) -> warp::reply::Response {
+    let mut response = warp::reply::Response::new(Body::from(text));
+    *response.status_mut() = status;
+    response
+}
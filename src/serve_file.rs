@@ -0,0 +1,200 @@
+use std::{
+    cmp::min,
+    convert::Infallible,
+    io,
+    ops::Bound,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use headers::{
+    AcceptRanges, ContentLength, ContentRange, ContentType, ETag, HeaderMapExt, IfMatch, IfModifiedSince,
+    IfNoneMatch, IfRange, IfUnmodifiedSince, LastModified, Range,
+};
+use hyper::{Body, HeaderMap, Method, Response, StatusCode};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::encryption::{ENCRYPTED_HEADER, ENCRYPTION, EncryptionKey, encrypt_stream, encrypted_size};
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Serves a single file, answering conditional (If-Match, If-None-Match,
+/// If-Modified-Since, If-Unmodified-Since) and range (Range, If-Range) requests
+/// as well as HEAD.
+pub async fn serve_file(path: PathBuf, method: Method, headers: HeaderMap) -> Result<Response<Body>, Infallible> {
+    if method != Method::GET && method != Method::HEAD {
+        return Ok(empty_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    let mut file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return Ok(empty_response(StatusCode::NOT_FOUND)),
+    };
+    let metadata = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(empty_response(StatusCode::NOT_FOUND)),
+    };
+    let length = metadata.len();
+    let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+    let etag = etag(length, modified);
+    let last_modified = LastModified::from(modified);
+
+    if let Some(status) = check_preconditions(&headers, &etag, modified) {
+        let mut response = empty_response(status);
+        response.headers_mut().typed_insert(etag);
+        response.headers_mut().typed_insert(last_modified);
+        return Ok(response);
+    }
+
+    // Ranges only apply if the client still has the same version of the file.
+    // We don't support multiple ranges, they would need a multipart/byteranges
+    // response, so we answer those with the whole file.
+    let range = headers
+        .typed_get::<Range>()
+        .filter(|range| range.iter().count() == 1)
+        .filter(|_| match headers.typed_get::<IfRange>() {
+            Some(if_range) => !if_range.is_modified(Some(&etag), Some(&last_modified)),
+            None => true,
+        });
+
+    let mut response = Response::new(Body::empty());
+    let (start, end) = match range.map(|range| satisfiable_range(&range, length)) {
+        Some(Some((start, end))) => {
+            *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+            response
+                .headers_mut()
+                .typed_insert(ContentRange::bytes(start..end, length).expect("valid range"));
+            (start, end)
+        }
+        Some(None) => {
+            let mut response = empty_response(StatusCode::RANGE_NOT_SATISFIABLE);
+            response.headers_mut().typed_insert(ContentRange::unsatisfied_bytes(length));
+            return Ok(response);
+        }
+        None => (0, length),
+    };
+
+    let headers = response.headers_mut();
+    headers.typed_insert(ContentLength(end - start));
+    headers.typed_insert(ContentType::from(mime_guess::from_path(&path).first_or_octet_stream()));
+    headers.typed_insert(AcceptRanges::bytes());
+    headers.typed_insert(etag);
+    headers.typed_insert(last_modified);
+
+    if method == Method::HEAD {
+        return Ok(response);
+    }
+
+    if start > 0 && file.seek(io::SeekFrom::Start(start)).await.is_err() {
+        return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+    *response.body_mut() = Body::wrap_stream(read_chunks(file, end - start));
+    Ok(response)
+}
+
+/// Serves `path` encrypted with `key`. There are no ranges or conditionals here,
+/// the encrypted stream can only be decrypted from its start.
+pub async fn serve_encrypted_file(path: PathBuf, key: EncryptionKey) -> Result<Response<Body>, Infallible> {
+    let file = match File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return Ok(empty_response(StatusCode::NOT_FOUND)),
+    };
+    let length = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return Ok(empty_response(StatusCode::NOT_FOUND)),
+    };
+
+    let ciphertext = encrypt_stream(&key, Box::pin(read_chunks(file, length)));
+    let mut response = Response::new(Body::wrap_stream(ciphertext));
+    let headers = response.headers_mut();
+    headers.typed_insert(ContentLength(encrypted_size(length)));
+    headers.typed_insert(ContentType::octet_stream());
+    headers.insert(ENCRYPTED_HEADER, ENCRYPTION.parse().expect("valid header value"));
+    Ok(response)
+}
+
+pub fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+// Changes whenever the file gets replaced or modified, good enough to tell
+// versions of a shared file apart.
+fn etag(length: u64, modified: SystemTime) -> ETag {
+    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("\"{:x}-{:x}-{:x}\"", length, modified.as_secs(), modified.subsec_nanos())
+        .parse()
+        .expect("valid etag")
+}
+
+// Evaluates the preconditions in the order of RFC 7232 section 6. Returns the
+// status to answer with if one of them fails.
+fn check_preconditions(headers: &HeaderMap, etag: &ETag, modified: SystemTime) -> Option<StatusCode> {
+    if let Some(if_match) = headers.typed_get::<IfMatch>() {
+        if !if_match.precondition_passes(etag) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(if_unmodified_since) = headers.typed_get::<IfUnmodifiedSince>() {
+        if !if_unmodified_since.precondition_passes(modified) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    }
+
+    if let Some(if_none_match) = headers.typed_get::<IfNoneMatch>() {
+        if !if_none_match.precondition_passes(etag) {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    } else if let Some(if_modified_since) = headers.typed_get::<IfModifiedSince>() {
+        if !if_modified_since.is_modified(modified) {
+            return Some(StatusCode::NOT_MODIFIED);
+        }
+    }
+    None
+}
+
+// Returns the requested range as start..end (end exclusive)
+fn satisfiable_range(range: &Range, length: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.iter().next()?;
+
+    let (start, end) = match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => (start, end.saturating_add(1)),
+        (Bound::Included(start), Bound::Unbounded) => (start, length),
+        // Suffix range, the last n bytes
+        (Bound::Unbounded, Bound::Included(last)) => (length.saturating_sub(last), length),
+        _ => return None,
+    };
+    let end = min(end, length);
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Stdin in chunks until it gets closed, for shares of unknown size
+pub fn read_stdin_chunks() -> impl futures::Stream<Item = io::Result<Vec<u8>>> {
+    futures::stream::try_unfold(tokio::io::stdin(), |mut stdin| async move {
+        let mut buf = vec![0; CHUNK_SIZE as usize];
+        let read = stdin.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buf.truncate(read);
+        Ok(Some((buf, stdin)))
+    })
+}
+
+pub fn read_chunks(file: File, length: u64) -> impl futures::Stream<Item = io::Result<Vec<u8>>> {
+    futures::stream::try_unfold((file, length), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0; min(CHUNK_SIZE, remaining) as usize];
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Shared file got shorter while sending it"));
+        }
+        buf.truncate(read);
+        Ok(Some((buf, (file, remaining - read as u64))))
+    })
+}
@@ -1,9 +1,11 @@
-use std::{num::ParseIntError, str::FromStr};
+use std::{num::ParseIntError, path::{Path, PathBuf}, pin::Pin, str::FromStr, sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}}, task::{Context, Poll}, time::{Duration, SystemTime}};
 
 use async_ctrlc::CtrlC;
-use futures::Future;
+use futures::{Future, Stream, StreamExt, channel::mpsc::{UnboundedSender, unbounded}};
+use headers::{ContentLength, ContentRange, HeaderMapExt};
+use hyper::{Body, Method, StatusCode, body::Bytes, header::{HeaderValue, IF_RANGE}};
 use warp::Filter;
-use crate::{tor_share_url::TorShareUrl, tor_utils::{TorDirOptions, TorDirectory, get_hidden_service_hostname, start_tor_hidden_service}};
+use crate::{archive::{ArchiveFormat, archive_name, stream_folder}, client_auth::{ClientAuthKey, remove_authorized_client}, content_disposition::attachment, digest::{SHA256SUMS, SHA256SUMS_HEADER, folder_sha256sums, sha256_file, sha256_hex, to_digest_header}, encryption::{ENCRYPTED_HEADER, ENCRYPTION, EncryptionKey, encrypt, encrypt_stream}, serve_file::{empty_response, read_stdin_chunks, serve_encrypted_file, serve_file}, identity::{IdentityKey, identity_dir, read_secret_key, write_secret_key}, tor_control::{self, OnionServiceKey, TorControl, TorControlOptions}, tor_share_url::TorShareUrl, tor_utils::{TorDirOptions, TorDirectory, get_hidden_service_hostname, start_tor_hidden_service}};
 
 use futures_lite::future::FutureExt;
 use structopt::StructOpt;
@@ -16,12 +18,18 @@ pub struct TorShareUrlOptions {
 }
 
 impl TorShareUrlOptions {
-    pub fn into_tor_share_url(&self, hostname: &str) -> TorShareUrl {
+    pub fn into_tor_share_url(&self, hostname: &str, words: bool) -> TorShareUrl {
         if let Some(path) = &self.path {
             TorShareUrl {
                 hostname: hostname.clone().into(),
                 path: path.clone().into(),
+                digest: None,
+                key: None,
+                client_auth: None,
+                size: None,
             }
+        } else if words {
+            TorShareUrl::short_random_path(hostname.clone().into())
         } else {
             TorShareUrl::random_path(hostname.clone().into())
         }
@@ -39,24 +47,100 @@ impl FromStr for TorShareUrlOptions {
 pub struct ShareOptions {
     #[structopt(flatten)]
     pub tor_dir_options: TorDirOptions,
+
+    #[structopt(flatten)]
+    pub tor_control_options: TorControlOptions,
     
+    /// File or folder to share, - shares whatever gets piped into stdin
     pub file_or_folder: String,
+
+    /// Name of the download when sharing stdin
+    #[structopt(long)]
+    pub name: Option<String>,
+
+    /// Name of an identity to share under. Its onion service key gets stored and
+    /// reused, so the share keeps its .onion hostname between runs.
+    #[structopt(long)]
     pub id: Option<String>,
 
+    /// Format in which folders get streamed to the downloader, either tar or zip
+    #[structopt(long, default_value = "tar")]
+    pub archive_format: ArchiveFormat,
+
+    /// Append the sha-256 digest to the share url, so downloaders can verify the
+    /// file against the link instead of trusting the sharing side
+    #[structopt(long)]
+    pub digest_in_url: bool,
+
+    /// Encrypt the shared data end-to-end. The key becomes part of the share url
+    /// and never reaches the webserver.
+    #[structopt(long)]
+    pub encrypt: bool,
+
+    /// Only let downloaders with the client authorization key (part of the share
+    /// url) reach the onion service
+    #[structopt(long)]
+    pub client_auth: bool,
+
+    /// Stop sharing as soon as the first download completed
+    #[structopt(long)]
+    pub once: bool,
+
+    /// Stop sharing after this many complete downloads, later requests get 410 Gone
+    #[structopt(long)]
+    pub max_downloads: Option<usize>,
+
+    /// Stop sharing after a duration (e.g. 30m or 2h) or at a point in time in
+    /// UTC (e.g. 2021-01-31T18:00:00Z)
+    #[structopt(long, parse(try_from_str = parse_expiry))]
+    pub expires_in: Option<SystemTime>,
+
+    /// Also print the share url as words, which are easier to read out than the
+    /// .onion hostname. Not available with --encrypt.
+    #[structopt(long)]
+    pub words: bool,
+
     #[structopt(flatten)]
     pub tor_share_url_options: TorShareUrlOptions,
 }
 
+const STDIN: &str = "-";
+
+// How long a folder share stays up for the manifest after its last download
+const MANIFEST_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
 pub enum ShareState<'a> {
     ConnectingStartingTor,
+    ConnectingComputingDigest,
     OnlineSharingNow(&'a TorShareUrl),
+    OnlineDownloadCompleted(&'a Downloads),
+    OnlineDownloadAborted(&'a Downloads),
     OfflineStopped,
+    OfflineExpired,
     OfflineError(String)
 }
 
-pub fn lossy_file_name(file: &warp::fs::File) -> Option<String> {
+pub struct Downloads {
+    pub completed: usize,
+    /// Downloads which didn't get through completely, e.g. because the
+    /// downloader went away
+    pub aborted: usize,
+}
+
+fn parse_expiry(expiry: &str) -> Result<SystemTime, String> {
+    if let Ok(duration) = humantime::parse_duration(expiry) {
+        return Ok(SystemTime::now() + duration);
+    }
+    let expires_at = humantime::parse_rfc3339_weak(expiry)
+        .map_err(|_| "Expected a duration like 30m or 2h, or a time like 2021-01-31T18:00:00Z".to_string())?;
+    if expires_at <= SystemTime::now() {
+        return Err(format!("{} is in the past, the share would expire right away", expiry));
+    }
+    Ok(expires_at)
+}
+
+pub fn lossy_file_name(file: &Path) -> Option<String> {
     let file_name = file
-        .path()
         .file_name()
         .unwrap_or_default()
         .to_str()
@@ -65,50 +149,499 @@ pub fn lossy_file_name(file: &warp::fs::File) -> Option<String> {
 }
 
 pub async fn share_file(share_options: &ShareOptions,  cb: impl Fn(ShareState)) {
-    let tor_dir = TorDirectory::from_general_options(&share_options.tor_dir_options); 
     let hidden_service_config = TorHiddenServiceConfig::from_random_port();  
-    let _torthread = start_tor_hidden_service(&tor_dir, &hidden_service_config);
 
-    let hidden_service_hostname =
-        get_hidden_service_hostname(&tor_dir)
+    // Stdin can only be read once, so it gets streamed to the first downloader
+    // without knowing its size or digest beforehand.
+    let from_stdin = share_options.file_or_folder == STDIN;
+    if from_stdin && share_options.digest_in_url {
+        cb(ShareState::OfflineError("--digest-in-url can't be used when sharing stdin, its digest is only known after sending it".into()));
+        return;
+    }
+    if share_options.words && share_options.encrypt {
+        cb(ShareState::OfflineError("--words can't be used with --encrypt, the key isn't part of the words".into()));
+        return;
+    }
+
+    cb(ShareState::ConnectingStartingTor);
+
+    let client_auth = if share_options.client_auth { Some(ClientAuthKey::generate()) } else { None };
+    let identity = match share_options.id.as_deref().map(identity_dir).transpose() {
+        Ok(identity) => identity,
+        Err(err) => {
+            cb(ShareState::OfflineError(err));
+            return;
+        }
+    };
+
+    // Either publish the onion service through the control port of an already
+    // running tor or start our own one with a hidden service directory.
+    let mut tor_control = None;
+    let mut tor_dir = None;
+    let hidden_service_hostname = if let Some(control_port) = &share_options.tor_control_options.control_port {
+        let onion_service = async {
+            let mut control = TorControl::connect(&share_options.tor_control_options, control_port).await?;
+            let key = match &identity {
+                Some(identity) => read_secret_key(identity)?.map_or(OnionServiceKey::New, |key| OnionServiceKey::Existing(key.to_base64())),
+                None => OnionServiceKey::Ephemeral,
+            };
+            let (service_id, new_secret_key) = control.add_onion(&hidden_service_config, client_auth.as_ref(), &key).await?;
+            if let (Some(identity), Some(secret_key)) = (&identity, new_secret_key) {
+                write_secret_key(identity, &IdentityKey::from_base64(&secret_key)?)?;
+            }
+            Ok::<_, tor_control::Error>((control, service_id))
+        };
+        match onion_service.await {
+            Ok((control, service_id)) => {
+                let hostname = format!("{}.onion", service_id);
+                tor_control = Some((control, service_id));
+                hostname
+            }
+            Err(err) => {
+                cb(ShareState::OfflineError(err.to_string()));
+                return;
+            }
+        }
+    } else {
+        let mut dir = TorDirectory::from_general_options(&share_options.tor_dir_options); 
+        if let Some(identity) = &identity {
+            dir.hidden_service = identity.to_string_lossy().into();
+        }
+        let authorized = match &client_auth {
+            Some(client_auth) => client_auth.authorize_client(&dir.hidden_service),
+            None => {
+                remove_authorized_client(&dir.hidden_service);
+                Ok(())
+            }
+        };
+        if let Err(err) = authorized {
+            cb(ShareState::OfflineError(format!("Couldn't write the client authorization: {}", err)));
+            dir.drop_if_temp();
+            return;
+        }
+        let _torthread = start_tor_hidden_service(&dir, &hidden_service_config);
+        let hostname = get_hidden_service_hostname(&dir)
             .unwrap_or("Error".to_string());
+        tor_dir = Some(dir);
+        hostname
+    };
+
+    // For folders we hand out a manifest with the digest of every file, the
+    // digest in the url is then the one of the manifest.
+    let shared_path = PathBuf::from(&share_options.file_or_folder);
+    if !from_stdin {
+        cb(ShareState::ConnectingComputingDigest);
+    }
+    let digest = if from_stdin {
+        Ok(String::new())
+    } else if shared_path.is_dir() {
+        folder_sha256sums(&shared_path)
+    } else {
+        sha256_file(&shared_path)
+    };
+    let digest = match digest {
+        Ok(digest) => digest,
+        Err(err) => {
+            cb(ShareState::OfflineError(err.to_string()));
+            return;
+        }
+    };
     
-    let tor_share_url = share_options.tor_share_url_options.into_tor_share_url(&hidden_service_hostname);
+    let mut tor_share_url = share_options.tor_share_url_options.into_tor_share_url(&hidden_service_hostname, share_options.words);
+    if share_options.digest_in_url {
+        tor_share_url.digest = Some(if shared_path.is_dir() { sha256_hex(digest.as_bytes()) } else { digest.clone() });
+    }
+    if share_options.encrypt {
+        tor_share_url.key = Some(EncryptionKey::generate());
+    }
+    tor_share_url.client_auth = client_auth;
+    if !from_stdin && !shared_path.is_dir() {
+        tor_share_url.size = shared_path.metadata().ok().map(|metadata| metadata.len());
+    }
 
-    cb(ShareState::ConnectingStartingTor);
+    let max_downloads = if share_options.once || from_stdin { Some(1) } else { share_options.max_downloads };
+    let limit_reached = |downloads: &Downloads| max_downloads.map_or(false, |max_downloads| downloads.completed >= max_downloads);
+    let (transfer_sender, mut transfers) = unbounded();
+    let settings = ShareSettings {
+        name: share_options.name.clone(),
+        archive_format: share_options.archive_format,
+        digest,
+        key: tor_share_url.key.clone(),
+        max_downloads,
+    };
+    let share = start_webserver(&hidden_service_config, share_options.file_or_folder.clone(), tor_share_url.path.clone(), settings, Transfers::new(transfer_sender));
+    let downloads = async {
+        let mut downloads = Downloads { completed: 0, aborted: 0 };
+        let mut manifests_served = 0;
+        while let Some(transfer) = transfers.next().await {
+            match transfer {
+                Transfer::Completed => {
+                    downloads.completed += 1;
+                    cb(ShareState::OnlineDownloadCompleted(&downloads));
+                }
+                Transfer::Aborted => {
+                    downloads.aborted += 1;
+                    cb(ShareState::OnlineDownloadAborted(&downloads));
+                }
+                Transfer::ManifestServed => manifests_served += 1,
+            }
+            if limit_reached(&downloads) {
+                break;
+            }
+            // Whatever got read from stdin is gone, there is nothing left to share
+            if from_stdin && downloads.aborted > 0 {
+                return;
+            }
+        }
+        if !limit_reached(&downloads) {
+            futures::future::pending::<()>().await;
+        }
+        // Downloaders verify the archive of a folder against its manifest, give
+        // them the chance to fetch it if they didn't do so yet
+        if shared_path.is_dir() {
+            let manifests = async {
+                while manifests_served < downloads.completed {
+                    match transfers.next().await {
+                        Some(Transfer::ManifestServed) => manifests_served += 1,
+                        Some(_) => {}
+                        None => break,
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(MANIFEST_GRACE_PERIOD, manifests).await;
+        }
+    };
 
-    let share = start_webserver(&hidden_service_config, share_options.file_or_folder.clone(), tor_share_url.path.clone());
+    let expired = async {
+        match share_options.expires_in {
+            Some(expires_at) => {
+                let expires_in = expires_at.duration_since(SystemTime::now()).unwrap_or_default();
+                tokio::time::delay_for(expires_in).await;
+            }
+            None => futures::future::pending::<()>().await,
+        }
+        true
+    };
 
     let ctrlc = CtrlC::new().expect("cannot create Ctrl+C handler?");
     cb(ShareState::OnlineSharingNow(&tor_share_url));
 
-    ctrlc.race(share).await;
-    tor_dir.drop_if_temp();
+    let stopped = async {
+        ctrlc.race(share).race(downloads).await;
+        false
+    };
+    let expired = stopped.race(expired).await;
+    if let Some((mut control, service_id)) = tor_control {
+        let _ = control.del_onion(&service_id).await;
+    }
+    if let Some(tor_dir) = tor_dir {
+        tor_dir.drop_if_temp();
+    }
+
+    if expired {
+        cb(ShareState::OfflineExpired);
+    } else {
+        cb(ShareState::OfflineStopped);
+    }
+}
 
-    cb(ShareState::OfflineStopped);
+/// How start_webserver serves a share
+struct ShareSettings {
+    /// Name of the download when sharing stdin
+    name: Option<String>,
+    archive_format: ArchiveFormat,
+    /// Hex encoded sha-256 of the shared file, or the sha256sums manifest if we
+    /// share a folder
+    digest: String,
+    /// If we got a key, everything gets encrypted with it and digests are only
+    /// handed out encrypted
+    key: Option<EncryptionKey>,
+    /// After this many complete downloads we answer with 410 Gone
+    max_downloads: Option<usize>,
 }
 
-fn start_webserver(tor_hidden_service_config: &TorHiddenServiceConfig, path: String, id: String) -> impl Future<Output = ()> {
+// Downloads get reported to `transfers`. If path is STDIN, stdin gets served once.
+fn start_webserver(tor_hidden_service_config: &TorHiddenServiceConfig, path: String, id: String, settings: ShareSettings, transfers: Transfers) -> impl Future<Output = ()> {
     pretty_env_logger::init();
+    let ShareSettings { name, archive_format, digest, key, max_downloads } = settings;
+
+    // Once the limit is reached, the shared data is gone until share_file stops
+    // us. The manifest of a folder stays, downloaders need it to verify the archive.
+    let limit_transfers = transfers.clone();
+    let limit_reached = warp::any().and_then(move || {
+        let limit_reached = max_downloads.map_or(false, |max_downloads| limit_transfers.completed() >= max_downloads);
+        async move {
+            if limit_reached {
+                Ok(empty_response(StatusCode::GONE))
+            } else {
+                Err(warp::reject::not_found())
+            }
+        }
+    });
 
     //println!("Serving file {} under /{}", path, id);
-    let add_headers = |file: warp::filters::fs::File| {
+    let folder = PathBuf::from(&path);
+    let routes = if path == STDIN {
+        // GET /{id} => stdin, chunked as we don't know its size
+        let filename = name.unwrap_or_else(|| "stdin".into());
+        let stdin = Arc::new(Mutex::new(Some(Box::pin(read_stdin_chunks()))));
+        let stdin_download = warp::path(id)
+            .and(warp::path::end())
+            .and(warp::get())
+            .map(move || {
+                let stdin = stdin.lock().unwrap().take();
+                let mut reply = match (stdin, &key) {
+                    (Some(stdin), Some(key)) => encrypted_response(Body::wrap_stream(encrypt_stream(key, stdin))),
+                    (Some(stdin), None) => warp::reply::Response::new(Body::wrap_stream(stdin)),
+                    // Somebody else got it already
+                    (None, _) => return empty_response(StatusCode::GONE),
+                };
+                insert_header(&mut reply, "Content-Disposition", &attachment(&filename));
+                report_transfer(reply, transfers.clone())
+            });
+        limit_reached.or(stdin_download).unify().boxed()
+    } else if folder.is_dir() {
+        // GET /{id} => {folder}.tar
+        let filename = archive_name(&folder, archive_format);
+        let archive_key = key.clone();
+        let manifest_transfers = transfers.clone();
+        let archive = warp::path(id.clone())
+            .and(warp::path::end())
+            .map(move || {
+                let archive = stream_folder(folder.clone(), archive_format);
+                let mut reply = match &archive_key {
+                    Some(key) => encrypted_response(Body::wrap_stream(encrypt_stream(key, archive))),
+                    None => warp::reply::Response::new(Body::wrap_stream(archive)),
+                };
+                insert_header(&mut reply, "Content-Type", archive_format.content_type());
+                insert_header(&mut reply, SHA256SUMS_HEADER, SHA256SUMS);
+                insert_header(&mut reply, "Content-Disposition", &attachment(&filename));
+                report_transfer(reply, transfers.clone())
+            });
+        // GET /{id}/sha256sums => digest of every file in {folder}
+        let digest_header = to_digest_header(&sha256_hex(digest.as_bytes()));
+        let sha256sums = warp::path(id)
+            .and(warp::path(SHA256SUMS))
+            .and(warp::path::end())
+            .map(move || {
+                let reply = match &key {
+                    Some(key) => encrypted_response(Body::wrap_stream(encrypt(key, digest.clone().into_bytes()))),
+                    None => {
+                        let mut reply = warp::reply::Response::new(digest.clone().into());
+                        insert_header(&mut reply, "Digest", &digest_header);
+                        reply
+                    }
+                };
+                report_manifest(reply, manifest_transfers.clone())
+            });
+        sha256sums.or(limit_reached).unify().or(archive).unify().boxed()
+    } else {
+        // GET|HEAD /{id}... => {file}
+        let file = PathBuf::from(&path);
         let filename = lossy_file_name(&file).unwrap_or_else(|| {
             println!("Couldn't get filename");
             "".into()
         });
-        warp::reply::with_header(
-            file,
-            "Content-Disposition",
-            format!("attachment; filename=\"{}\"", filename),
-        )
+        // The digest of the plaintext would tell the webserver what we share
+        let digest_header = if key.is_none() { Some(to_digest_header(&digest)) } else { None };
+        let file_download = warp::path(id)
+            .and(warp::method())
+            .and(warp::header::headers_cloned())
+            .and_then(move |method: Method, headers| {
+                let file = file.clone();
+                let key = key.clone();
+                let transfers = transfers.clone();
+                async move {
+                    let get = method == Method::GET;
+                    let resumed = headers.contains_key(IF_RANGE);
+                    let reply = match key {
+                        Some(key) => serve_encrypted_file(file, key).await,
+                        None => serve_file(file, method, headers).await,
+                    };
+                    reply.map(|reply| {
+                        if get && is_complete_download(&reply, resumed) {
+                            report_transfer(reply, transfers)
+                        } else {
+                            reply
+                        }
+                    })
+                }
+            })
+            .map(move |mut reply: warp::reply::Response| {
+                if let Some(digest_header) = &digest_header {
+                    insert_header(&mut reply, "Digest", digest_header);
+                }
+                insert_header(&mut reply, "Content-Disposition", &attachment(&filename));
+                reply
+            });
+        limit_reached.or(file_download).unify().boxed()
     };
-    let examples = warp::path(id).and(warp::fs::file(path)).map(add_headers);
-
-    // GET /{id}... => {file}
-    let routes = examples;
 
     println!("Starting http server on port {}", tor_hidden_service_config.local_port);
 
     warp::serve(routes).run(([127, 0, 0, 1], tor_hidden_service_config.local_port))
 }
+
+fn encrypted_response(body: Body) -> warp::reply::Response {
+    let mut reply = warp::reply::Response::new(body);
+    insert_header(&mut reply, ENCRYPTED_HEADER, ENCRYPTION);
+    reply
+}
+
+// A download is complete if the client gets the whole file or the rest of it
+// when resuming an interrupted download. Resuming sends If-Range, other ranges
+// like the end of a file or a seek in a video aren't downloads of their own.
+fn is_complete_download(reply: &warp::reply::Response, resumed: bool) -> bool {
+    match reply.status() {
+        StatusCode::OK => true,
+        StatusCode::PARTIAL_CONTENT if resumed => reply
+            .headers()
+            .typed_get::<ContentRange>()
+            .and_then(|content_range| Some(content_range.bytes_range()?.1 + 1 == content_range.bytes_len()?))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+enum Transfer {
+    Completed,
+    Aborted,
+    /// Somebody got the sha256sums of a shared folder
+    ManifestServed,
+}
+
+/// Keeps track of the downloads going through the webserver and reports them
+/// to share_file.
+#[derive(Clone)]
+struct Transfers {
+    events: UnboundedSender<Transfer>,
+    completed: Arc<AtomicUsize>,
+}
+
+impl Transfers {
+    fn new(events: UnboundedSender<Transfer>) -> Self {
+        Transfers { events, completed: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    fn report(&self, transfer: Transfer) {
+        if let Transfer::Completed = transfer {
+            self.completed.fetch_add(1, Ordering::SeqCst);
+        }
+        let _ = self.events.unbounded_send(transfer);
+    }
+
+    fn completed(&self) -> usize {
+        self.completed.load(Ordering::SeqCst)
+    }
+}
+
+/// Passes the body through and reports the download as completed once all of
+/// it got handed to the client, or as aborted if the body gets dropped before,
+/// because the client went away.
+fn report_transfer(reply: warp::reply::Response, transfers: Transfers) -> warp::reply::Response {
+    // hyper stops polling the body as soon as Content-Length bytes are written,
+    // so we can't rely on seeing the end of the stream.
+    let remaining = reply.headers().typed_get::<ContentLength>().map(|content_length| content_length.0);
+    if remaining == Some(0) {
+        transfers.report(Transfer::Completed);
+        return reply;
+    }
+    reply.map(|body| Body::wrap_stream(TransferBody { body, remaining, transfers: Some(transfers) }))
+}
+
+/// Reports the manifest as served once all of it got handed to the client.
+fn report_manifest(reply: warp::reply::Response, transfers: Transfers) -> warp::reply::Response {
+    let served = futures::stream::once(async move { transfers.report(Transfer::ManifestServed) })
+        .filter_map(|()| async { None::<Result<Bytes, hyper::Error>> });
+    reply.map(|body| Body::wrap_stream(body.chain(served)))
+}
+
+struct TransferBody {
+    body: Body,
+    remaining: Option<u64>,
+    transfers: Option<Transfers>,
+}
+
+impl TransferBody {
+    fn complete(&mut self) {
+        if let Some(transfers) = self.transfers.take() {
+            transfers.report(Transfer::Completed);
+        }
+    }
+}
+
+impl Stream for TransferBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = Pin::new(&mut self.body).poll_next(cx);
+        match &next {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let chunk_size = chunk.len() as u64;
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining = remaining.saturating_sub(chunk_size);
+                    if *remaining == 0 {
+                        self.complete();
+                    }
+                }
+            }
+            Poll::Ready(None) => self.complete(),
+            _ => {}
+        }
+        next
+    }
+}
+
+impl Drop for TransferBody {
+    fn drop(&mut self) {
+        if let Some(transfers) = self.transfers.take() {
+            transfers.report(Transfer::Aborted);
+        }
+    }
+}
+
+fn insert_header(reply: &mut warp::reply::Response, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        reply.headers_mut().insert(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(status: StatusCode, content_range: Option<&str>) -> warp::reply::Response {
+        let mut reply = warp::reply::Response::new(Body::empty());
+        *reply.status_mut() = status;
+        if let Some(content_range) = content_range {
+            insert_header(&mut reply, "Content-Range", content_range);
+        }
+        reply
+    }
+
+    #[test]
+    fn counts_whole_files() {
+        assert!(is_complete_download(&reply(StatusCode::OK, None), false));
+        // If-Range didn't match, so the client got everything again
+        assert!(is_complete_download(&reply(StatusCode::OK, None), true));
+    }
+
+    #[test]
+    fn counts_resumed_downloads() {
+        // bytes=100- with If-Range
+        assert!(is_complete_download(&reply(StatusCode::PARTIAL_CONTENT, Some("bytes 100-999/1000")), true));
+    }
+
+    #[test]
+    fn ignores_other_ranges() {
+        // bytes=-3, e.g. a player looking for metadata at the end
+        assert!(!is_complete_download(&reply(StatusCode::PARTIAL_CONTENT, Some("bytes 7-9/10")), false));
+        // bytes=500-, a seek without resuming anything
+        assert!(!is_complete_download(&reply(StatusCode::PARTIAL_CONTENT, Some("bytes 500-999/1000")), false));
+        // bytes=2-5
+        assert!(!is_complete_download(&reply(StatusCode::PARTIAL_CONTENT, Some("bytes 2-5/10")), false));
+        assert!(!is_complete_download(&reply(StatusCode::PARTIAL_CONTENT, Some("bytes 2-5/10")), true));
+        assert!(!is_complete_download(&reply(StatusCode::RANGE_NOT_SATISFIABLE, None), true));
+        assert!(!is_complete_download(&reply(StatusCode::NOT_MODIFIED, None), false));
+    }
+}
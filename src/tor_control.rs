@@ -0,0 +1,191 @@
+use std::fs;
+
+use error_chain::error_chain;
+use structopt::StructOpt;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{client_auth::ClientAuthKey, tor_utils::TorHiddenServiceConfig};
+
+#[derive(Debug, StructOpt)]
+pub struct TorControlOptions {
+    /// Publish the share through the control port of an already running tor (e.g. 127.0.0.1:9051)
+    #[structopt(long, env = "TOR_CONTROL_PORT")]
+    pub control_port: Option<String>,
+    /// Password for the control port, if tor is configured with HashedControlPassword
+    #[structopt(long, env = "TOR_CONTROL_PASSWORD")]
+    pub control_password: Option<String>,
+    /// Path to the control auth cookie, defaults to the one tor tells us about
+    #[structopt(long, env = "TOR_CONTROL_COOKIE")]
+    pub control_cookie: Option<String>,
+}
+
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+    }
+
+    errors {
+        ControlReply(reply: String) {
+            description("tor control port returned an error")
+            display("Tor control port replied with: {}", reply)
+        }
+        UnsupportedAuthentication(methods: String) {
+            description("no supported authentication method")
+            display("No supported authentication method for the tor control port, tor offers: {}", methods)
+        }
+    }
+}
+
+pub enum OnionServiceKey {
+    /// Let tor generate a key and forget it again, nothing gets written to disk
+    Ephemeral,
+    /// Let tor generate a key and hand it to us, so we can reuse it later on
+    New,
+    /// Base64 encoded key of an existing onion service
+    Existing(String),
+}
+
+/// Connection to the control port of a running tor. Onion services created with
+/// `add_onion` are ephemeral and only live as long as this connection.
+pub struct TorControl {
+    stream: BufReader<TcpStream>,
+}
+
+impl TorControl {
+    pub async fn connect(options: &TorControlOptions, control_port: &str) -> Result<Self> {
+        let stream = TcpStream::connect(control_port).await?;
+        let mut tor_control = TorControl { stream: BufReader::new(stream) };
+        tor_control.authenticate(options).await?;
+        Ok(tor_control)
+    }
+
+    async fn authenticate(&mut self, options: &TorControlOptions) -> Result<()> {
+        let protocol_info = self.send_command("PROTOCOLINFO 1").await?;
+        let auth_line = protocol_info
+            .iter()
+            .find(|line| line.starts_with("AUTH "))
+            .cloned()
+            .unwrap_or_default();
+        let methods = protocol_info_value(&auth_line, "METHODS=").unwrap_or_default();
+        let has_method = |method: &str| methods.split(',').any(|m| m == method);
+
+        let command = if let Some(password) = &options.control_password {
+            format!("AUTHENTICATE {}", quote(password))
+        } else if has_method("NULL") {
+            "AUTHENTICATE".into()
+        } else if has_method("COOKIE") {
+            let cookie_file = options
+                .control_cookie
+                .clone()
+                .or_else(|| protocol_info_value(&auth_line, "COOKIEFILE="))
+                .ok_or_else(|| ErrorKind::UnsupportedAuthentication(methods.clone()))?;
+            let cookie: String = fs::read(cookie_file)?.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("AUTHENTICATE {}", cookie)
+        } else {
+            return Err(ErrorKind::UnsupportedAuthentication(methods).into());
+        };
+
+        self.send_command(&command).await?;
+        Ok(())
+    }
+
+    /// Creates an ephemeral v3 onion service pointing to our webserver and returns
+    /// its service id (the hostname without ".onion") and the key tor generated,
+    /// if we asked for it. With `client_auth` only clients with that key can reach
+    /// the service (needs tor 0.4.6 or newer).
+    pub async fn add_onion(&mut self, config: &TorHiddenServiceConfig, client_auth: Option<&ClientAuthKey>, key: &OnionServiceKey) -> Result<(String, Option<String>)> {
+        let (key, flags) = match key {
+            OnionServiceKey::Ephemeral => ("NEW:ED25519-V3".to_string(), " Flags=DiscardPK"),
+            OnionServiceKey::New => ("NEW:ED25519-V3".to_string(), ""),
+            OnionServiceKey::Existing(secret_key) => (format!("ED25519-V3:{}", secret_key), ""),
+        };
+        let mut command = format!(
+            "ADD_ONION {}{} Port={},{}:{}",
+            key, flags, config.remote_port, config.local_host, config.local_port
+        );
+        if let Some(client_auth) = client_auth {
+            command.push_str(&format!(" ClientAuthV3={}", client_auth.public_key()));
+        }
+        let reply = self.send_command(&command).await?;
+        let service_id = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("ServiceID="))
+            .map(|service_id| service_id.to_string())
+            .ok_or_else(|| Error::from(ErrorKind::ControlReply(reply.join(" "))))?;
+        let secret_key = reply
+            .iter()
+            .find_map(|line| line.strip_prefix("PrivateKey=ED25519-V3:"))
+            .map(|secret_key| secret_key.to_string());
+        Ok((service_id, secret_key))
+    }
+
+    pub async fn del_onion(&mut self, service_id: &str) -> Result<()> {
+        self.send_command(&format!("DEL_ONION {}", service_id)).await?;
+        Ok(())
+    }
+
+    // Sends a command and returns the lines of the reply without the status code.
+    // Fails if tor doesn't answer with 250.
+    async fn send_command(&mut self, command: &str) -> Result<Vec<String>> {
+        self.stream.write_all(format!("{}\r\n", command).as_bytes()).await?;
+        self.stream.flush().await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(ErrorKind::ControlReply("Connection closed".into()).into());
+            }
+            let line = line.trim_end().to_string();
+            if line.len() < 4 {
+                return Err(ErrorKind::ControlReply(line).into());
+            }
+            let (status, rest) = line.split_at(3);
+            if status != "250" {
+                return Err(ErrorKind::ControlReply(line.clone()).into());
+            }
+            let (separator, content) = rest.split_at(1);
+            lines.push(content.to_string());
+            match separator {
+                " " => break,
+                "+" => {
+                    // Data reply, lasts until a line with a single dot
+                    loop {
+                        let mut data = String::new();
+                        if self.stream.read_line(&mut data).await? == 0 || data.trim_end() == "." {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(lines)
+    }
+}
+
+// Reads KEY=value or KEY="quoted value" out of a PROTOCOLINFO line.
+fn protocol_info_value(line: &str, key: &str) -> Option<String> {
+    let (_, value) = line.split_once(key)?;
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut unquoted = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => unquoted.push(chars.next()?),
+                '"' => return Some(unquoted),
+                c => unquoted.push(c),
+            }
+        }
+        None
+    } else {
+        value.split(' ').next().map(|value| value.to_string())
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -1,48 +1,361 @@
+use std::{convert::TryInto, fmt, str::FromStr};
+
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use url::Url;
 
-use crate::share::TorShareUrlOptions;
+use crate::{client_auth::ClientAuthKey, encryption::EncryptionKey, onion_address::{OnionAddressError, onion_address, parse_onion_address}, share::TorShareUrlOptions, word_code::{from_word_code, is_word_code, to_word_code}};
 
 #[derive(Debug)]
+pub enum TorShareUrlError {
+    InvalidHostname(OnionAddressError),
+    InvalidUrl(url::ParseError),
+    Malformed(&'static str),
+}
+
+impl fmt::Display for TorShareUrlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TorShareUrlError::InvalidHostname(e) => write!(f, "{}", e),
+            TorShareUrlError::InvalidUrl(e) => write!(f, "Not a valid url: {}", e),
+            TorShareUrlError::Malformed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl From<OnionAddressError> for TorShareUrlError {
+    fn from(e: OnionAddressError) -> Self {
+        TorShareUrlError::InvalidHostname(e)
+    }
+}
+
+impl From<url::ParseError> for TorShareUrlError {
+    fn from(e: url::ParseError) -> Self {
+        TorShareUrlError::InvalidUrl(e)
+    }
+}
+
+impl From<&'static str> for TorShareUrlError {
+    fn from(reason: &'static str) -> Self {
+        TorShareUrlError::Malformed(reason)
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct TorShareUrl {
     pub hostname: String,
     pub path: String,
+    /// Hex encoded sha-256 of the shared file (or of the manifest of a shared
+    /// folder), appended to the url as #sha256=<digest>
+    pub digest: Option<String>,
+    /// Key of an encrypted share, appended to the url as #key=<key>. Like the
+    /// digest it lives in the fragment and never gets sent to the sharing side.
+    pub key: Option<EncryptionKey>,
+    /// Private key of a share with onion client authorization, appended to the
+    /// url as #auth=<key>. Without it tor can't reach the onion service at all.
+    pub client_auth: Option<ClientAuthKey>,
+    /// Size of the shared file in bytes, only part of torshare:// links as #size=<bytes>
+    pub size: Option<u64>,
 }
 
-impl TorShareUrl {
-    pub fn from_str(url: &str) -> Result<Self, &str> {
-        if let Some((hostname, path)) = url.split_once('/') {
-            if !hostname.ends_with(".onion") {
-                Err("Hostname doesn't end with .onion")
-            } else {
-                Ok(TorShareUrl {
-                    hostname: hostname.into(),
-                    path: path.into(),
-                })
+const TORSHARE_SCHEME: &str = "torshare";
+// Version of the torshare:// link format, bumped whenever links stop being
+// understood by older versions of torshare
+const TORSHARE_LINK_VERSION: &str = "1";
+
+// Path length of shares which get read out as words, ~47 bits. Nobody can try
+// that many paths through tor and the onion address is only known to whoever
+// we share it with anyway.
+const SHORT_PATH_LENGTH: usize = 8;
+
+// Links pasted from chats and mails often end up with the surrounding
+// punctuation, none of it can be part of a share url.
+const SURROUNDING_PUNCTUATION: &[char] = &['<', '>', '(', ')', '[', ']', '"', '\'', '.', ',', ';', ':', '!', '?'];
+
+impl FromStr for TorShareUrl {
+    type Err = TorShareUrlError;
+
+    /// Accepts torshare://<hostname>/<path>#v=1&<parameters> links, the short form
+    /// <hostname>/<path>#<parameters>, full urls with scheme, trailing slash or
+    /// query string as well as word codes.
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let url = url.trim().trim_matches(SURROUNDING_PUNCTUATION);
+        if is_word_code(url) {
+            return Self::from_word_code(url);
+        }
+        let url = if url.contains("://") {
+            Url::parse(url)?
+        } else {
+            Url::parse(&format!("http://{}", url))?
+        };
+        if !matches!(url.scheme(), "http" | "https" | TORSHARE_SCHEME) {
+            return Err("Url has to start with torshare://, http:// or nothing at all".into());
+        }
+        // Only special schemes like http get their host lowercased by Url
+        let hostname = url.host_str().unwrap_or_default().to_ascii_lowercase();
+        parse_onion_address(&hostname)?;
+        let path = url.path().trim_matches('/');
+        if path.is_empty() {
+            return Err("Url doesn't contain a path after the hostname".into());
+        }
+
+        let mut digest = None;
+        let mut key = None;
+        let mut client_auth = None;
+        let mut size = None;
+        let mut version = None;
+        for parameter in url.fragment().unwrap_or_default().split('&').filter(|parameter| !parameter.is_empty()) {
+            match parameter.split_once('=') {
+                Some(("sha256", value)) => {
+                    if value.len() != 64 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+                        return Err("Digest has to be in the form of sha256=<64 hex characters>".into());
+                    }
+                    digest = Some(value.to_ascii_lowercase());
+                }
+                Some(("key", value)) => key = Some(EncryptionKey::from_str(value)?),
+                Some(("auth", value)) => client_auth = Some(ClientAuthKey::from_str(value)?),
+                Some(("size", value)) => size = Some(value.parse().map_err(|_| "Size has to be in the form of size=<bytes>")?),
+                Some(("v", value)) => version = Some(value),
+                _ => return Err("Unknown parameter after #, expected sha256=, key=, auth= or size=".into()),
             }
+        }
+        if url.scheme() == TORSHARE_SCHEME && version != Some(TORSHARE_LINK_VERSION) {
+            return Err("Link is from a different version of torshare, expected #v=1".into());
+        }
+        Ok(TorShareUrl {
+            hostname,
+            path: path.into(),
+            digest,
+            key,
+            client_auth,
+            size,
+        })
+    }
+}
+
+impl fmt::Display for TorShareUrl {
+    /// The short form, <hostname>/<path>#<parameters>
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fragment = self.fragment();
+        if fragment.is_empty() {
+            write!(f, "{}/{}", &self.hostname, &self.path)
         } else {
-            Err("Hostname doesn't contain a / delimiter")
+            write!(f, "{}/{}#{}", &self.hostname, &self.path, fragment.join("&"))
         }
     }
+}
+
+impl TorShareUrl {
 
     pub fn random_path(hostname: String) -> Self {
+        Self::random_path_of_length(hostname, 30)
+    }
+
+    /// For shares which get read out as words. The path is part of the word code,
+    /// every four characters of it are three more words.
+    pub fn short_random_path(hostname: String) -> Self {
+        Self::random_path_of_length(hostname, SHORT_PATH_LENGTH)
+    }
+
+    fn random_path_of_length(hostname: String, length: usize) -> Self {
         let rand_path: String = thread_rng()
             .sample_iter(&Alphanumeric)
-            .take(30)
+            .take(length)
             .map(char::from)
             .collect();
 
         TorShareUrl {
             hostname: hostname.into(),
             path: rand_path,
+            digest: None,
+            key: None,
+            client_auth: None,
+            size: None,
+        }
+    }
+
+    /// torshare://<hostname>/<path>#v=1&<parameters>, everything needed to download
+    /// and check a share in one link
+    pub fn to_torshare_uri(&self) -> String {
+        let mut fragment = vec![format!("v={}", TORSHARE_LINK_VERSION)];
+        fragment.extend(self.fragment());
+        if let Some(size) = self.size {
+            fragment.push(format!("size={}", size));
         }
+        format!("{}://{}/{}#{}", TORSHARE_SCHEME, &self.hostname, &self.path, fragment.join("&"))
     }
 
-    pub fn to_string(&self) -> String {
-        format!("{}/{}", &self.hostname, &self.path)
+    /// Onion public key and path as words, see word_code.rs. Keys and digest aren't
+    /// part of it, they would make it far too long to read out. With a path from
+    /// short_random_path these are 33 words.
+    pub fn to_word_code(&self) -> Option<String> {
+        let public_key = parse_onion_address(&self.hostname).ok()?;
+        Some(to_word_code(&[&public_key[..], self.path.as_bytes()].concat()))
+    }
+
+    fn from_word_code(words: &str) -> Result<Self, TorShareUrlError> {
+        let data = from_word_code(words)?;
+        if data.len() <= 32 {
+            return Err("Word code is incomplete, is a word missing?".into());
+        }
+        let (public_key, path) = data.split_at(32);
+        Ok(TorShareUrl {
+            hostname: onion_address(public_key.try_into().expect("split at 32 bytes")),
+            path: String::from_utf8(path.to_vec()).map_err(|_| "Word code doesn't contain a valid path")?,
+            digest: None,
+            key: None,
+            client_auth: None,
+            size: None,
+        })
+    }
+
+    fn fragment(&self) -> Vec<String> {
+        let mut fragment = Vec::new();
+        if let Some(client_auth) = &self.client_auth {
+            fragment.push(format!("auth={}", client_auth));
+        }
+        if let Some(key) = &self.key {
+            fragment.push(format!("key={}", key));
+        }
+        if let Some(digest) = &self.digest {
+            fragment.push(format!("sha256={}", digest));
+        }
+        fragment
     }
 
     pub fn to_url(&self) -> String {
         format!("http://{}/{}", &self.hostname, &self.path)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn hostname() -> String {
+        onion_address(&[7; 32])
+    }
+
+    fn parse(url: &str) -> TorShareUrl {
+        url.parse().unwrap_or_else(|e| panic!("{}: {}", url, e))
+    }
+
+    #[test]
+    fn parses_full_urls() {
+        let hostname = hostname();
+        for url in &[
+            format!("{}/abc", hostname),
+            format!("http://{}/abc", hostname),
+            format!("https://{}/abc", hostname),
+            format!("{}/abc/", hostname),
+            format!("http://{}/abc?query=1", hostname),
+            format!("<http://{}/abc>", hostname),
+            format!("({}/abc).", hostname),
+            format!("  \"{}/abc\",\n", hostname),
+        ] {
+            let tor_share_url = parse(url);
+            assert_eq!(tor_share_url.hostname, hostname);
+            assert_eq!(tor_share_url.path, "abc");
+        }
+    }
+
+    #[test]
+    fn lowercases_hosts() {
+        let hostname = hostname();
+        for url in &[
+            format!("{}/AbC", hostname.to_uppercase()),
+            format!("HTTP://{}/AbC", hostname.to_uppercase()),
+            format!("torshare://{}/AbC#v=1", hostname.to_uppercase()),
+        ] {
+            let tor_share_url = parse(url);
+            assert_eq!(tor_share_url.hostname, hostname);
+            assert_eq!(tor_share_url.path, "AbC");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_urls() {
+        let hostname = hostname();
+        for url in &[
+            hostname.clone(),
+            format!("{}/", hostname),
+            format!("ftp://{}/abc", hostname),
+            format!("{}/abc#unknown=1", hostname),
+            format!("{}/abc#sha256=123", hostname),
+            format!("torshare://{}/abc", hostname),
+            format!("torshare://{}/abc#v=2", hostname),
+            "example.com/abc".to_string(),
+        ] {
+            assert!(url.parse::<TorShareUrl>().is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn round_trips_every_fragment() {
+        for &digest in &[false, true] {
+            for &key in &[false, true] {
+                for &client_auth in &[false, true] {
+                    let mut tor_share_url = TorShareUrl::random_path(hostname());
+                    if digest {
+                        tor_share_url.digest = Some(EMPTY_SHA256.into());
+                    }
+                    if key {
+                        tor_share_url.key = Some(EncryptionKey::generate());
+                    }
+                    if client_auth {
+                        tor_share_url.client_auth = Some(ClientAuthKey::generate());
+                    }
+                    assert_eq!(parse(&tor_share_url.to_string()), tor_share_url);
+
+                    tor_share_url.size = Some(42);
+                    assert_eq!(parse(&tor_share_url.to_torshare_uri()), tor_share_url);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn display_drops_size() {
+        let mut tor_share_url = TorShareUrl::random_path(hostname());
+        tor_share_url.size = Some(42);
+        let short_form = parse(&tor_share_url.to_string());
+        assert_eq!(short_form.size, None);
+        assert_ne!(short_form, tor_share_url);
+    }
+
+    fn words(tor_share_url: &TorShareUrl) -> Vec<String> {
+        let word_code = tor_share_url.to_word_code().unwrap();
+        word_code.split('-').filter(|word| !word.is_empty()).map(String::from).collect()
+    }
+
+    #[test]
+    fn round_trips_word_codes() {
+        let tor_share_url = TorShareUrl::short_random_path(hostname());
+        let word_code = tor_share_url.to_word_code().unwrap();
+        assert_eq!(words(&tor_share_url).len(), 33);
+        for code in &[word_code.clone(), words(&tor_share_url).join(" "), word_code.to_uppercase(), format!(" {}.\n", word_code)] {
+            assert_eq!(parse(code), tor_share_url);
+        }
+    }
+
+    #[test]
+    fn rejects_word_codes_with_a_dropped_word() {
+        let tor_share_url = TorShareUrl::short_random_path(hostname());
+        for index in 0..33 {
+            let mut words = words(&tor_share_url);
+            words.remove(index);
+            assert!(words.join(" ").parse::<TorShareUrl>().is_err(), "without word {}", index);
+        }
+    }
+
+    #[test]
+    fn rejects_word_codes_with_swapped_words() {
+        let tor_share_url = TorShareUrl::short_random_path(hostname());
+        let mut words = words(&tor_share_url);
+        let index = (0..32).find(|&i| words[i] != words[i + 1]).unwrap();
+        words.swap(index, index + 1);
+        assert!(words.join(" ").parse::<TorShareUrl>().is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{path::Path, thread};
+use std::{fmt, path::Path, str::FromStr, thread};
 use std::thread::JoinHandle;
 use std::time::Duration;
 
@@ -11,6 +11,8 @@ use std::io::prelude::*;
 use rand::prelude::*;
 use structopt::StructOpt;
 
+use crate::client_auth::create_private_dir;
+
 #[derive(Debug, StructOpt)]
 pub struct TorDirOptions {
     #[structopt(long, env = "TOR_DIR")]
@@ -62,11 +64,20 @@ impl TorDirectory {
 
     }
 
-    pub fn drop_if_temp(&self) {
-        if let Some(tempdir) = &self.tempdir {
-            drop(tempdir);
+    pub fn drop_if_temp(self) {
+        if let Some(tempdir) = self.tempdir {
+            let _ = tempdir.close();
         }
     }
+
+    pub fn log_file(&self) -> String {
+        Path::new(&self.tor).join("notices.log").to_string_lossy().into()
+    }
+
+    /// ClientOnionAuthDir of our tor, holds the keys for onion services with client authorization
+    pub fn client_auth_dir(&self) -> String {
+        Path::new(&self.tor).join("onion_auth").to_string_lossy().into()
+    }
 }
 
 
@@ -106,12 +117,24 @@ pub fn start_tor_hidden_service(tor_dir: &TorDirectory, config: &TorHiddenServic
 }
 
 pub fn start_tor_socks5(tor_dir: &TorDirectory, socks5: &TorSocks5) -> JoinHandle<std::result::Result<u8, libtor::Error>> {
+    // Truncate the log of previous runs, otherwise we would read an old bootstrap
+    // progress in get_tor_bootstrap_progress.
+    let _ = File::create(tor_dir.log_file());
+    // Tor refuses to start if the ClientOnionAuthDir doesn't exist
+    let _ = create_private_dir(Path::new(&tor_dir.client_auth_dir()));
     let torthread = Tor::new()
         .flag(TorFlag::DataDirectory(tor_dir.tor.as_str().into()))
         .flag(TorFlag::ControlPort(0))
         .flag(TorFlag::SocksPort(socks5.port))
+        .flag(TorFlag::ClientOnionAuthDir(tor_dir.client_auth_dir()))
+        .flag(TorFlag::LogTo(
+            LogLevel::Notice,
+            LogDestination::File(tor_dir.log_file()),
+        ))
         //.flag(TorFlag::LogTo(LogLevel::Err, LogDestination::Stderr))
-        //.flag(TorFlag::Quiet())
+        // Tor logs to stdout until it has read LogTo, which would end up in the
+        // middle of downloads to stdout
+        .flag(TorFlag::Quiet())
         .start_background();
     return torthread;
 }
@@ -137,12 +160,43 @@ pub fn get_hidden_service_hostname(tor_dir: &TorDirectory) -> std::io::Result<St
     return Ok(contents.trim().to_string());
 }
 
+// Tor logs lines like "[notice] Bootstrapped 45% (requesting_descriptors): ...".
+// We take the last one, 100 means we are ready to build circuits.
+pub fn get_tor_bootstrap_progress(tor_dir: &TorDirectory) -> u8 {
+    let mut contents = String::new();
+    if File::open(tor_dir.log_file()).and_then(|mut file| file.read_to_string(&mut contents)).is_err() {
+        return 0;
+    }
+
+    contents
+        .lines()
+        .rev()
+        .find_map(|line| {
+            let (_, progress) = line.split_once("Bootstrapped ")?;
+            let (percent, _) = progress.split_once('%')?;
+            percent.parse::<u8>().ok()
+        })
+        .unwrap_or(0)
+}
+
+// Tor logs why it couldn't start as warnings followed by an error like "[err] Reading
+// config failed--see warnings above.", so the first of them is the most telling one.
+pub fn get_tor_error(tor_dir: &TorDirectory) -> Option<String> {
+    let mut contents = String::new();
+    File::open(tor_dir.log_file()).and_then(|mut file| file.read_to_string(&mut contents)).ok()?;
+
+    contents.lines().find_map(|line| {
+        let (_, error) = line.split_once("[warn] ").or_else(|| line.split_once("[err] "))?;
+        Some(error.to_string())
+    })
+}
 
 // ToDo: This is currently a very dumb approach. But should work in most cases and can
 // easily get fixed by running torshare again.
 pub fn random_port() -> u16 {
     rand::thread_rng().gen_range(1024..65535)
 }
+#[derive(Debug, Clone)]
 pub struct TorSocks5 {
     pub host: String,
     pub port: u16
@@ -153,8 +207,26 @@ impl TorSocks5 {
         let rand_port = random_port();
         Self { host: "127.0.0.1".into(), port: rand_port }
     }
+}
+
+impl FromStr for TorSocks5 {
+    type Err = &'static str;
+
+    fn from_str(proxy: &str) -> Result<Self, Self::Err> {
+        let proxy = proxy
+            .trim_start_matches("socks5h://")
+            .trim_start_matches("socks5://");
+        if let Some((host, port)) = proxy.rsplit_once(':') {
+            let port = port.trim_end_matches('/').parse::<u16>().map_err(|_| "Port of the socks proxy is not a number")?;
+            Ok(Self { host: host.into(), port })
+        } else {
+            Err("Socks proxy has to be in the form of host:port")
+        }
+    }
+}
 
-    pub fn to_string(&self) -> String {
-       format!("socks5h://{}:{}", self.host.clone(), &self.port)
+impl fmt::Display for TorSocks5 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "socks5h://{}:{}", self.host, self.port)
     }
 }
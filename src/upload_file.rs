@@ -0,0 +1,155 @@
+use std::{path::PathBuf, thread, time::{self, Instant}};
+
+use futures::{StreamExt, TryStreamExt, channel::mpsc::unbounded, future::{self, Either}};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use reqwest::header::CONTENT_LENGTH;
+use structopt::StructOpt;
+
+use crate::{client_auth::ClientAuthKey, download_file::{FileInformation, TorClient}, serve_file::read_chunks, share::lossy_file_name, tor_share_url::TorShareUrl, tor_utils::{TorDirOptions, TorSocks5}};
+
+#[derive(Debug, StructOpt)]
+pub struct UploadOptions {
+    #[structopt(flatten)]
+    pub tor_dir_options: TorDirOptions,
+    /// Use an already running tor (e.g. 127.0.0.1:9050) instead of starting our own
+    #[structopt(long, env = "TOR_SOCKS_PROXY")]
+    pub socks_proxy: Option<TorSocks5>,
+    /// Client authorization key of a private share, if it isn't part of the url
+    #[structopt(long, env = "TOR_CLIENT_AUTH")]
+    pub client_auth: Option<ClientAuthKey>,
+    /// Url printed by `torshare receive`
+    pub url: TorShareUrl,
+    pub file: String,
+}
+
+pub struct UploadProgress {
+    pub uploaded_megabytes: f64,
+    pub percent: f32,
+    pub speed: f64
+}
+
+pub enum UploadState<'a> {
+    ConnectingWithoutClientAuth,
+    ConnectingWaitingForTor,
+    ConnectingBootstrappingTor(u8),
+    ConnectingWaitingForProxy(&'a TorSocks5),
+    ConnectedWaitingForPeer,
+    ConnectedUploading(&'a FileInformation, UploadProgress),
+    ConnectedUploaded(&'a FileInformation),
+    DisconnectedError(String)
+}
+
+pub async fn upload_file(upload_options: &UploadOptions, cb: impl Fn(UploadState)) {
+    let tor_client = TorClient::new(&upload_options.tor_dir_options, &upload_options.socks_proxy);
+    let tor_share_url = &upload_options.url;
+
+    match tor_client.authorize_for(upload_options.client_auth.as_ref(), tor_share_url) {
+        Ok(true) => {}
+        Ok(false) => cb(UploadState::ConnectingWithoutClientAuth),
+        Err(e) => {
+            cb(UploadState::DisconnectedError(e));
+            tor_client.stop();
+            return;
+        }
+    }
+
+    cb(UploadState::ConnectingWaitingForTor);
+    if let Err(e) = tor_client.start(|bootstrap_progress| cb(UploadState::ConnectingBootstrappingTor(bootstrap_progress))) {
+        cb(UploadState::DisconnectedError(e));
+        tor_client.stop();
+        return;
+    }
+    let client = tor_client.http_client();
+
+    let path = PathBuf::from(&upload_options.file);
+    let name = lossy_file_name(&path).unwrap_or_default();
+    // PUT /{id}/{file name}
+    let url = format!("{}/{}", tor_share_url.to_url(), utf8_percent_encode(&name, NON_ALPHANUMERIC));
+    loop {
+        let file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                cb(UploadState::DisconnectedError(e.to_string()));
+                break;
+            }
+        };
+        let size = match file.metadata().await {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => {
+                cb(UploadState::DisconnectedError(format!("{} is not a file", upload_options.file)));
+                break;
+            }
+            Err(e) => {
+                cb(UploadState::DisconnectedError(e.to_string()));
+                break;
+            }
+        };
+        let file_information = FileInformation { name: name.clone(), size: size as f64 / 1000000.0 };
+
+        // The body gets read by reqwest, it tells us through the channel how far it got
+        let (progress_sender, mut progress) = unbounded();
+        let body = read_chunks(file, size).inspect_ok(move |chunk| {
+            let _ = progress_sender.unbounded_send(chunk.len());
+        });
+        let mut upload = Box::pin(
+            client
+                .put(&url)
+                .header(CONTENT_LENGTH, size)
+                .body(reqwest::Body::wrap_stream(body))
+                .send(),
+        );
+
+        let mut uploaded_bytes: u64 = 0;
+        let mut last_progress = Instant::now();
+        let mut uploaded_bytes_last_second = 0;
+        // bytes per second
+        let mut speed: f64 = -1.0;
+        let result = loop {
+            match future::select(upload, progress.next()).await {
+                Either::Left((result, _)) => break result,
+                Either::Right((None, pending_upload)) => break pending_upload.await,
+                Either::Right((Some(chunk_size), pending_upload)) => {
+                    upload = pending_upload;
+                    uploaded_bytes += chunk_size as u64;
+                    uploaded_bytes_last_second += chunk_size;
+                    let elapsed_time_as_secs = last_progress.elapsed().as_secs_f64();
+                    if elapsed_time_as_secs > 0.5 {
+                        speed = uploaded_bytes_last_second as f64 / 1000000.0 / elapsed_time_as_secs;
+                        uploaded_bytes_last_second = 0;
+                        last_progress = Instant::now();
+                    } else if speed == -1.0 {
+                        speed = uploaded_bytes_last_second as f64 / 1000000.0 / elapsed_time_as_secs;
+                    }
+                    let percent = if size == 0 { 100.0 } else { uploaded_bytes as f32 / size as f32 * 100.0 };
+                    cb(UploadState::ConnectedUploading(&file_information, UploadProgress {
+                        uploaded_megabytes: uploaded_bytes as f64 / 1000000.0,
+                        percent,
+                        speed,
+                    }));
+                }
+            }
+        };
+
+        match result {
+            Err(e) if uploaded_bytes == 0 => {
+                // Nothing got through yet, the receiving side isn't reachable (yet)
+                if e.to_string().contains("Proxy server unreachable") {
+                    cb(UploadState::ConnectingWaitingForProxy(&tor_client.tor_socks5));
+                } else {
+                    cb(UploadState::ConnectedWaitingForPeer);
+                }
+                thread::sleep(time::Duration::from_millis(50));
+                continue;
+            }
+            Err(e) => cb(UploadState::DisconnectedError(e.to_string())),
+            Ok(response) if response.status().is_success() => cb(UploadState::ConnectedUploaded(&file_information)),
+            Ok(response) => {
+                let status = response.status();
+                let reason = response.text().await.unwrap_or_default();
+                cb(UploadState::DisconnectedError(format!("Receiving side answered with {}: {}", status, reason.trim())));
+            }
+        }
+        break;
+    }
+    tor_client.stop();
+}
@@ -0,0 +1,38 @@
+use sha2::{Digest, Sha256};
+
+// Share urls as a sequence of words which can be read out, e.g. over the phone.
+// Uses the mnemonic encoding by Oren Tirosh (three words for every four bytes),
+// the last words are a checksum to catch typos and missing words.
+
+const CHECKSUM_SIZE: usize = 4;
+
+pub fn to_word_code(data: &[u8]) -> String {
+    mnemonic::to_string([data, &checksum(data)].concat())
+}
+
+pub fn from_word_code(words: &str) -> Result<Vec<u8>, &'static str> {
+    let mut decoded = Vec::new();
+    mnemonic::decode(words.to_ascii_lowercase(), &mut decoded).map_err(|e| match e {
+        mnemonic::Error::UnrecognizedWord => "Word code contains a word which isn't on the word list",
+        _ => "Word code is incomplete, is a word missing?",
+    })?;
+    if decoded.len() < CHECKSUM_SIZE {
+        return Err("Word code is incomplete, is a word missing?");
+    }
+    let (data, expected) = decoded.split_at(decoded.len() - CHECKSUM_SIZE);
+    if expected != checksum(data) {
+        return Err("Word code has a wrong checksum, a word is probably mistyped or missing");
+    }
+    Ok(data.to_vec())
+}
+
+/// Word codes are nothing but letters and separators, urls always contain a dot
+pub fn is_word_code(code: &str) -> bool {
+    code.chars().all(|c| c.is_ascii_alphabetic() || c == '-' || c == ' ')
+        && code.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let digest = Sha256::digest(data);
+    [digest[0], digest[1], digest[2], digest[3]]
+}